@@ -0,0 +1,87 @@
+use hmac::{Hmac, Mac};
+use rand::prelude::*;
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Expiry timestamp + HMAC-SHA256 tag
+pub const COOKIE_SIZE: usize = 8 + 32;
+const COOKIE_LIFETIME: Duration = Duration::from_secs(10);
+
+// Signs challenge cookies so the server doesn't have to remember who
+// it has challenged. A cookie is only valid for the address it was
+// sent to, until it expires.
+pub struct ChallengeKey {
+    key: [u8; 32],
+}
+
+impl ChallengeKey {
+    pub fn generate() -> Self {
+        ChallengeKey {
+            key: thread_rng().gen(),
+        }
+    }
+
+    pub fn cookie(&self, addr: &SocketAddr, now: SystemTime) -> Vec<u8> {
+        let expires = unix_secs(now + COOKIE_LIFETIME);
+        let mut cookie = expires.to_be_bytes().to_vec();
+        cookie.extend_from_slice(&self.mac(addr, expires).finalize().into_bytes());
+        cookie
+    }
+
+    pub fn verify(&self, addr: &SocketAddr, cookie: &[u8], now: SystemTime) -> bool {
+        if cookie.len() < COOKIE_SIZE {
+            return false;
+        }
+
+        let mut expires = [0; 8];
+        expires.copy_from_slice(&cookie[..8]);
+        let expires = u64::from_be_bytes(expires);
+        if expires < unix_secs(now) {
+            return false;
+        }
+
+        self.mac(addr, expires)
+            .verify_slice(&cookie[8..COOKIE_SIZE])
+            .is_ok()
+    }
+
+    fn mac(&self, addr: &SocketAddr, expires: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("any key size is valid");
+        mac.update(addr.to_string().as_bytes());
+        mac.update(&expires.to_be_bytes());
+        mac
+    }
+}
+
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie_verify() {
+        let key = ChallengeKey::generate();
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        let other = "127.0.0.1:1235".parse().unwrap();
+        let now = SystemTime::now();
+
+        let cookie = key.cookie(&addr, now);
+        assert_eq!(cookie.len(), COOKIE_SIZE);
+        assert!(key.verify(&addr, &cookie, now));
+        assert!(!key.verify(&other, &cookie, now));
+        assert!(!key.verify(&addr, &cookie, now + Duration::from_secs(60)));
+        assert!(!ChallengeKey::generate().verify(&addr, &cookie, now));
+
+        let mut forged = cookie.clone();
+        forged[0] ^= 1;
+        assert!(!key.verify(&addr, &forged, now));
+    }
+}
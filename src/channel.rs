@@ -0,0 +1,89 @@
+use crate::sequence::Sequence;
+
+pub type ChannelId = u8;
+
+// Handed out when a message is queued, to match it up with its delivery
+// report. Only unique among messages still in flight on the channel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MessageId {
+    pub channel: ChannelId,
+    pub sequence: Sequence,
+}
+
+// Channel id + block length
+pub const CHANNEL_HEADER_LENGTH: usize = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChannelKind {
+    // Resent until acked, delivered in the order they were queued
+    ReliableOrdered,
+    // Resent until acked, delivered as soon as they arrive
+    ReliableUnordered,
+    // Sent once, delivered as soon as they arrive
+    Unreliable,
+    // Sent once, anything older than the newest received message is dropped
+    UnreliableSequenced,
+}
+
+impl ChannelKind {
+    pub fn is_reliable(self) -> bool {
+        match self {
+            ChannelKind::ReliableOrdered | ChannelKind::ReliableUnordered => true,
+            ChannelKind::Unreliable | ChannelKind::UnreliableSequenced => false,
+        }
+    }
+}
+
+// Channel ids are indexes into this list
+pub const DEFAULT_CHANNELS: [ChannelKind; 4] = [
+    ChannelKind::ReliableOrdered,
+    ChannelKind::ReliableUnordered,
+    ChannelKind::Unreliable,
+    ChannelKind::UnreliableSequenced,
+];
+
+pub fn write_block(vec: &mut Vec<u8>, channel: ChannelId, data: &mut Vec<u8>) {
+    let len = data.len() as u16;
+    vec.push(channel);
+    vec.push((len >> 8) as u8);
+    vec.push(len as u8);
+    vec.append(data);
+}
+
+// Splits a packet payload into (channel, data) blocks
+pub fn read_blocks(slice: &[u8]) -> Result<Vec<(ChannelId, &[u8])>, &'static str> {
+    let len = slice.len();
+    let mut blocks = Vec::new();
+    let mut index = 0;
+
+    while index < len {
+        if len - index < CHANNEL_HEADER_LENGTH {
+            return Err("truncated channel header");
+        }
+        let channel = slice[index];
+        let size = ((slice[index + 1] as usize) << 8) | slice[index + 2] as usize;
+        index += CHANNEL_HEADER_LENGTH;
+
+        if index + size > len {
+            return Err("truncated channel block");
+        }
+        blocks.push((channel, &slice[index..index + size]));
+        index += size;
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_read_blocks() {
+        let mut vec = Vec::new();
+        write_block(&mut vec, 0, &mut b"hi".to_vec());
+        write_block(&mut vec, 3, &mut b"ho".to_vec());
+        let blocks = read_blocks(&vec).unwrap();
+        assert_eq!(blocks, vec![(0, &b"hi"[..]), (3, &b"ho"[..])]);
+        assert!(read_blocks(&vec[..vec.len() - 1]).is_err());
+    }
+}
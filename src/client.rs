@@ -1,78 +1,291 @@
 use std::collections::VecDeque;
 use std::io;
 use std::net::{SocketAddr, UdpSocket};
-use std::sync::mpsc::TryRecvError;
-use std::thread;
+use std::time::Duration;
 
-use crate::connection::Connection;
+use crate::channel::{ChannelId, MessageId};
+use crate::connection::{Connection, ConnectionState, PacketEvent, StateReason};
+use crate::crypto::Keys;
+use crate::message_queue::{Delivery, SendError};
+use crate::sequence::Sequence;
+use crate::stats::ConnectionStats;
+use crate::token::ConnectToken;
+use crate::transport::Transport;
 
-pub struct Client {
-    socket: UdpSocket,
+// How a connection attempt ended, or a connected client was dropped
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ClientEvent {
+    Connected,
+    ConnectionDenied,
+    ServerFull,
+    // The server never answered
+    Timeout,
+    Disconnected(StateReason),
+    // A reliable message arrived, this long after it first went out
+    MessageAcked(MessageId, Duration),
+    // A reliable message wasn't acked before its deadline
+    MessageGivenUp(MessageId),
+    // A payload packet went out with this sequence, followed later by
+    // `PacketAcked` or `PacketLost` for the same sequence
+    PacketSent(Sequence),
+    PacketAcked(Sequence),
+    PacketLost(Sequence),
+}
+
+pub struct Client<T: Transport = UdpSocket> {
+    socket: T,
     local_addr: SocketAddr,
     remote_addr: Option<SocketAddr>,
     buffer: [u8; 1504],
     connection: Option<Connection>,
-    message_queue: VecDeque<Vec<u8>>,
+    // Session keys and private token while connecting with a token, and
+    // the servers it lists that haven't been tried yet
+    token: Option<(Keys, Vec<u8>)>,
+    servers_left: VecDeque<SocketAddr>,
+    // Last state reported through `events`
+    reported: Option<ConnectionState>,
+    events: VecDeque<ClientEvent>,
 }
 
-impl Client {
+impl Client<UdpSocket> {
     pub fn new(local_addr: SocketAddr) -> Self {
         let socket = UdpSocket::bind(local_addr).expect("Could not bind to socket");
         socket.set_nonblocking(true).unwrap();
+        Client::with_transport(socket)
+    }
+}
+
+impl<T: Transport> Client<T> {
+    pub fn with_transport(socket: T) -> Self {
+        let local_addr = socket.local_addr().expect("Transport has no local address");
         Client {
             socket,
             local_addr,
             remote_addr: None,
             buffer: [0; 1504],
             connection: None,
-            message_queue: VecDeque::new(),
+            token: None,
+            servers_left: VecDeque::new(),
+            reported: None,
+            events: VecDeque::new(),
         }
     }
 
+    // Starts an unencrypted handshake, requests are resent on each
+    // `send_next` until the server answers or the attempt times out
     pub fn connect(&mut self, remote: SocketAddr) -> io::Result<()> {
+        self.token = None;
+        self.servers_left.clear();
+        self.start(remote);
+        self.send_next().map(|_| ())
+    }
+
+    // Connects to the servers in the token in turn, using the session
+    // keys the backend gave us, until one takes us or all have denied us
+    // or timed out. The keys are only good for this one connection, each
+    // server won't take the same token twice.
+    pub fn connect_with_token(&mut self, token: ConnectToken) -> io::Result<()> {
+        let mut servers: VecDeque<SocketAddr> = token.server_addresses.into_iter().collect();
+        let remote = match servers.pop_front() {
+            Some(remote) => remote,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "token lists no servers",
+                ))
+            }
+        };
+        self.token = Some((token.keys, token.private));
+        self.servers_left = servers;
+        self.start(remote);
+        self.send_next().map(|_| ())
+    }
+
+    // The first request goes out on the next `send_next`
+    fn start(&mut self, remote: SocketAddr) {
         self.remote_addr = Some(remote);
-        let mut new_conn = Connection::new(self.local_addr, self.remote_addr.unwrap());
-        while let Some(message) = self.message_queue.pop_front() {
-            new_conn.queue_message(&message);
+        let mut new_conn = Connection::new(self.local_addr, remote, self.socket.now());
+        if let Some((keys, private)) = &self.token {
+            new_conn.set_keys(keys.clone());
+            new_conn.set_token(private.clone());
         }
         self.connection = Some(new_conn);
-        Ok(())
+        self.reported = None;
     }
 
-    pub fn send_next(&mut self) -> Result<usize, std::io::Error> {
-        if let Some(conn) = &mut self.connection {
-            return conn.send(&mut self.socket);
+    pub fn send_next(&mut self) -> io::Result<usize> {
+        let conn = match &mut self.connection {
+            Some(conn) => conn,
+            None => return Err(not_connected()),
+        };
+        let now = self.socket.now();
+        let mut sent = 0;
+        if conn.ready_to_send(now) {
+            if let Some(sequence) = conn.send(now) {
+                self.events.push_back(ClientEvent::PacketSent(sequence));
+            }
+        } else {
+            conn.update(now);
+        }
+        while let Some(datagram) = conn.poll_transmit() {
+            sent += self.socket.send_to(&datagram, conn.remote_addr())?;
         }
-        panic!("connect first");
+        self.check_deliveries();
+        self.check_state();
+        Ok(sent)
     }
 
-    pub fn recv(&mut self) -> Result<usize, TryRecvError> {
-        if let Ok((amt, addr)) = self.socket.recv_from(&mut self.buffer) {
-            if addr != self.remote_addr.unwrap() {
-                return Ok(0);
-            }
-            let data = self.buffer[..amt].to_vec();
-            match &mut self.connection {
-                Some(conn) => conn.receive_packet(&data),
-                None => panic!("connect first"),
-            };
-            Ok(amt)
-        } else {
-            Err(TryRecvError::Empty)
+    // Handles one datagram, `WouldBlock` once there are none left
+    pub fn recv(&mut self) -> io::Result<usize> {
+        let conn = match &mut self.connection {
+            Some(conn) => conn,
+            None => return Err(not_connected()),
+        };
+        let (amt, addr) = self.socket.recv_from(&mut self.buffer)?;
+        if Some(addr) != self.remote_addr {
+            return Ok(0);
+        }
+        conn.receive_packet(&self.buffer[..amt], self.socket.now());
+        self.check_deliveries();
+        self.check_state();
+        Ok(amt)
+    }
+
+    // Messages can be queued as soon as `connect` has been called, they
+    // go out once the handshake is done. Before that there is nothing to
+    // queue them on and they are refused with `NotConnected`.
+    pub fn queue_message(
+        &mut self,
+        channel: ChannelId,
+        message: Vec<u8>,
+    ) -> Result<MessageId, SendError> {
+        match &mut self.connection {
+            Some(conn) => conn.queue_message(channel, &message),
+            None => Err(SendError::NotConnected),
         }
     }
 
-    pub fn queue_message(&mut self, message: Vec<u8>) {
+    // Reliable messages not acked within `timeout` are given up on
+    pub fn queue_message_with_deadline(
+        &mut self,
+        channel: ChannelId,
+        message: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<MessageId, SendError> {
+        let deadline = self.socket.now() + timeout;
         match &mut self.connection {
-            Some(conn) => conn.queue_message(&message),
-            None => self.message_queue.push_back(message),
+            Some(conn) => conn.queue_message_with_deadline(channel, &message, deadline),
+            None => Err(SendError::NotConnected),
         }
     }
 
-    pub fn recv_messages(&mut self) -> Option<Vec<Vec<u8>>> {
+    // Slots taken on `channel`, see `Connection::send_queue_len`
+    pub fn send_queue_len(&self, channel: ChannelId) -> Option<usize> {
+        self.connection
+            .as_ref()
+            .and_then(|conn| conn.send_queue_len(channel))
+    }
+
+    pub fn send_queue_capacity(&self, channel: ChannelId) -> Option<usize> {
+        self.connection
+            .as_ref()
+            .and_then(|conn| conn.send_queue_capacity(channel))
+    }
+
+    pub fn recv_messages(&mut self) -> Option<Vec<(ChannelId, Vec<u8>)>> {
         if let Some(conn) = &mut self.connection {
             return Some(conn.recv_messages());
         }
         None
     }
+
+    pub fn state(&self) -> Option<(ConnectionState, StateReason)> {
+        self.connection
+            .as_ref()
+            .map(|conn| (conn.state(), conn.state_reason()))
+    }
+
+    // Time to send less, the connection has dropped its send rate
+    pub fn congested(&self) -> bool {
+        self.connection
+            .as_ref()
+            .is_some_and(|conn| conn.congested())
+    }
+
+    pub fn stats(&self) -> Option<ConnectionStats> {
+        self.connection
+            .as_ref()
+            .map(|conn| conn.stats(self.socket.now()))
+    }
+
+    // Everything that has happened since the last call
+    pub fn events(&mut self) -> impl Iterator<Item = ClientEvent> + '_ {
+        self.events.drain(..)
+    }
+
+    // Disconnect packets go out on the following calls to `send_next`
+    pub fn disconnect(&mut self) {
+        if let Some(conn) = &mut self.connection {
+            conn.disconnect(self.socket.now());
+        }
+        self.check_state();
+    }
+
+    fn check_deliveries(&mut self) {
+        let conn = match &mut self.connection {
+            Some(conn) => conn,
+            None => return,
+        };
+        for (id, delivery) in conn.deliveries() {
+            let event = match delivery {
+                Delivery::Acked(time) => ClientEvent::MessageAcked(id, time),
+                Delivery::GivenUp => ClientEvent::MessageGivenUp(id),
+            };
+            self.events.push_back(event);
+        }
+        for event in conn.packet_events() {
+            let event = match event {
+                PacketEvent::Acked(sequence) => ClientEvent::PacketAcked(sequence),
+                PacketEvent::Lost(sequence) => ClientEvent::PacketLost(sequence),
+            };
+            self.events.push_back(event);
+        }
+    }
+
+    fn check_state(&mut self) {
+        let (state, reason) = match self.state() {
+            Some(state) => state,
+            None => return,
+        };
+        let event = match state {
+            _ if self.reported == Some(state) => return,
+            ConnectionState::Connected => ClientEvent::Connected,
+            // Move on to the token's next server, if it lists another
+            ConnectionState::Disconnected
+                if matches!(
+                    reason,
+                    StateReason::Denied | StateReason::ServerFull | StateReason::ConnectTimedOut
+                ) && !self.servers_left.is_empty() =>
+            {
+                let remote = self.servers_left.pop_front().unwrap();
+                self.start(remote);
+                return;
+            }
+            ConnectionState::Disconnected => match reason {
+                StateReason::Denied => ClientEvent::ConnectionDenied,
+                StateReason::ServerFull => ClientEvent::ServerFull,
+                StateReason::ConnectTimedOut => ClientEvent::Timeout,
+                reason => ClientEvent::Disconnected(reason),
+            },
+            // Nothing to report on the way through the handshake or
+            // while disconnect packets go out
+            _ => return,
+        };
+        self.reported = Some(state);
+        self.events.push_back(event);
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "connect first")
 }
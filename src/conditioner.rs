@@ -0,0 +1,433 @@
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::transport::Transport;
+
+// One way delay before jitter is added
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    Uniform(Duration, Duration),
+    Normal { mean: Duration, std_dev: Duration },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        match *self {
+            Latency::Fixed(delay) => delay,
+            Latency::Uniform(min, max) if max > min => min + (max - min).mul_f64(rng.gen::<f64>()),
+            Latency::Uniform(min, _) => min,
+            Latency::Normal { mean, std_dev } => {
+                // Box-Muller, clamped so nothing arrives before it was sent
+                let u1 = 1.0 - rng.gen::<f64>();
+                let u2 = rng.gen::<f64>();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                let secs = mean.as_secs_f64() + z * std_dev.as_secs_f64();
+                Duration::from_secs_f64(secs.max(0.0))
+            }
+        }
+    }
+}
+
+// Gilbert-Elliott loss. The link flips between a good and a bad state
+// and drops with a different probability in each, which gives the
+// bursts of loss real networks have rather than evenly spread drops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BurstLoss {
+    pub good_to_bad: f32,
+    pub bad_to_good: f32,
+    pub good_loss: f32,
+    pub bad_loss: f32,
+}
+
+impl BurstLoss {
+    pub fn none() -> Self {
+        BurstLoss::uniform(0.0)
+    }
+
+    // Independent drops, the same in both states
+    pub fn uniform(loss: f32) -> Self {
+        BurstLoss {
+            good_to_bad: 0.0,
+            bad_to_good: 1.0,
+            good_loss: loss,
+            bad_loss: loss,
+        }
+    }
+}
+
+impl Default for BurstLoss {
+    fn default() -> Self {
+        BurstLoss::none()
+    }
+}
+
+// Probabilities are per packet, the default config passes everything
+// straight through
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConfig {
+    pub latency: Latency,
+    // Extra delay picked uniformly up to this
+    pub jitter: Duration,
+    pub loss: BurstLoss,
+    pub duplicate: f32,
+    // Chance a packet is held back by `reorder_delay`, letting later
+    // packets overtake it
+    pub reorder: f32,
+    pub reorder_delay: Duration,
+    // Chance a single bit of the packet is flipped
+    pub corrupt: f32,
+    // Bytes per second, 0 for no cap
+    pub bandwidth: u32,
+    // Packets that would wait longer than this for bandwidth are dropped
+    pub queue_limit: Duration,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        LinkConfig {
+            latency: Latency::Fixed(Duration::from_millis(0)),
+            jitter: Duration::from_millis(0),
+            loss: BurstLoss::none(),
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(50),
+            corrupt: 0.0,
+            bandwidth: 0,
+            queue_limit: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct InFlight {
+    deliver_at: Instant,
+    // Keeps packets due at the same time in the order they were sent
+    order: u64,
+    addr: SocketAddr,
+    data: Vec<u8>,
+}
+
+// One direction of an impaired link. Time is passed in so it can be
+// driven by a real or a simulated clock.
+pub struct Link {
+    config: LinkConfig,
+    rng: StdRng,
+    bad_state: bool,
+    link_free_at: Option<Instant>,
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    sent: u64,
+}
+
+impl Link {
+    pub fn new(config: LinkConfig, seed: u64) -> Self {
+        Link {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            bad_state: false,
+            link_free_at: None,
+            in_flight: BinaryHeap::new(),
+            sent: 0,
+        }
+    }
+
+    pub fn config(&self) -> &LinkConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: LinkConfig) {
+        self.config = config;
+    }
+
+    // `addr` is carried through untouched, it is whatever the caller
+    // needs back from `poll`
+    pub fn send(&mut self, now: Instant, addr: SocketAddr, data: &[u8]) {
+        if self.lose() {
+            return;
+        }
+
+        let mut depart = now;
+        if self.config.bandwidth > 0 {
+            let start = self.link_free_at.map_or(now, |free| free.max(now));
+            if start - now > self.config.queue_limit {
+                return;
+            }
+            let transmit = data.len() as f64 / f64::from(self.config.bandwidth);
+            depart = start + Duration::from_secs_f64(transmit);
+            self.link_free_at = Some(depart);
+        }
+
+        let copies = if self.roll(self.config.duplicate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut delay = self.config.latency.sample(&mut self.rng);
+            delay += self.config.jitter.mul_f64(self.rng.gen::<f64>());
+            if self.roll(self.config.reorder) {
+                delay += self.config.reorder_delay;
+            }
+
+            let mut data = data.to_vec();
+            if !data.is_empty() && self.roll(self.config.corrupt) {
+                let bit = self.rng.gen_range(0, data.len() * 8);
+                data[bit / 8] ^= 1 << (bit % 8);
+            }
+
+            self.sent += 1;
+            self.in_flight.push(Reverse(InFlight {
+                deliver_at: depart + delay,
+                order: self.sent,
+                addr,
+                data,
+            }));
+        }
+    }
+
+    // Next packet that has arrived by `now`
+    pub fn poll(&mut self, now: Instant) -> Option<(SocketAddr, Vec<u8>)> {
+        match self.in_flight.peek() {
+            Some(Reverse(packet)) if packet.deliver_at <= now => {}
+            _ => return None,
+        }
+        self.in_flight
+            .pop()
+            .map(|Reverse(packet)| (packet.addr, packet.data))
+    }
+
+    pub fn next_delivery(&self) -> Option<Instant> {
+        self.in_flight
+            .peek()
+            .map(|Reverse(packet)| packet.deliver_at)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    fn lose(&mut self) -> bool {
+        let loss = self.config.loss;
+        let flip = if self.bad_state {
+            loss.bad_to_good
+        } else {
+            loss.good_to_bad
+        };
+        if self.roll(flip) {
+            self.bad_state = !self.bad_state;
+        }
+        self.roll(if self.bad_state {
+            loss.bad_loss
+        } else {
+            loss.good_loss
+        })
+    }
+
+    fn roll(&mut self, chance: f32) -> bool {
+        chance > 0.0 && self.rng.gen::<f32>() < chance
+    }
+}
+
+// Wraps a transport and impairs what goes through it, each direction
+// separately. Delayed packets are let through on later calls to
+// `send_to` or `recv_from`, so it needs polling like the socket does.
+pub struct LinkConditioner<T: Transport> {
+    inner: T,
+    incoming: Link,
+    outgoing: Link,
+}
+
+impl<T: Transport> LinkConditioner<T> {
+    pub fn new(inner: T, incoming: LinkConfig, outgoing: LinkConfig, seed: u64) -> Self {
+        LinkConditioner {
+            inner,
+            incoming: Link::new(incoming, seed),
+            outgoing: Link::new(outgoing, seed.wrapping_add(1)),
+        }
+    }
+
+    pub fn incoming(&mut self) -> &mut Link {
+        &mut self.incoming
+    }
+
+    pub fn outgoing(&mut self) -> &mut Link {
+        &mut self.outgoing
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    fn flush(&mut self, now: Instant) {
+        while let Some((addr, data)) = self.outgoing.poll(now) {
+            // The caller has long since been told this was sent, a
+            // failure now is just more loss
+            let _ = self.inner.send_to(&data, addr);
+        }
+    }
+}
+
+impl<T: Transport> Transport for LinkConditioner<T> {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let now = self.inner.now();
+        self.outgoing.send(now, addr, buf);
+        self.flush(now);
+        Ok(buf.len())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let now = self.inner.now();
+        self.flush(now);
+        loop {
+            match self.inner.recv_from(buf) {
+                Ok((amt, from)) => self.incoming.send(now, from, &buf[..amt]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        match self.incoming.poll(now) {
+            Some((from, data)) => {
+                let amt = data.len().min(buf.len());
+                buf[..amt].copy_from_slice(&data[..amt]);
+                Ok((amt, from))
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:1000".parse().unwrap()
+    }
+
+    fn received(link: &mut Link, now: Instant) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        while let Some((_, data)) = link.poll(now) {
+            packets.push(data);
+        }
+        packets
+    }
+
+    #[test]
+    fn test_latency_and_reproducible() {
+        let config = LinkConfig {
+            latency: Latency::Uniform(Duration::from_millis(50), Duration::from_millis(100)),
+            jitter: Duration::from_millis(20),
+            loss: BurstLoss::uniform(0.3),
+            duplicate: 0.1,
+            reorder: 0.1,
+            ..LinkConfig::default()
+        };
+        let start = Instant::now();
+        let mut a = Link::new(config, 7);
+        let mut b = Link::new(config, 7);
+        for i in 0..200u8 {
+            a.send(start, addr(), &[i]);
+            b.send(start, addr(), &[i]);
+        }
+
+        assert!(a.poll(start + Duration::from_millis(49)).is_none());
+        let from_a = received(&mut a, start + Duration::from_secs(1));
+        assert_eq!(from_a, received(&mut b, start + Duration::from_secs(1)));
+        assert!(from_a.len() > 100 && from_a.len() < 180);
+        assert_eq!(a.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_burst_loss() {
+        let config = LinkConfig {
+            loss: BurstLoss {
+                good_to_bad: 0.05,
+                bad_to_good: 0.2,
+                good_loss: 0.0,
+                bad_loss: 1.0,
+            },
+            ..LinkConfig::default()
+        };
+        let now = Instant::now();
+        let mut link = Link::new(config, 1);
+        for i in 0..1000u16 {
+            link.send(now, addr(), &i.to_be_bytes());
+        }
+        let ids: Vec<u16> = received(&mut link, now)
+            .iter()
+            .map(|data| u16::from_be_bytes([data[0], data[1]]))
+            .collect();
+
+        // Losses come in runs, so there are far fewer gaps than losses
+        let lost = 1000 - ids.len();
+        let gaps = ids.windows(2).filter(|w| w[1] != w[0] + 1).count();
+        assert!(lost > 100);
+        assert!(gaps * 2 < lost);
+    }
+
+    #[test]
+    fn test_bandwidth_and_corruption() {
+        let config = LinkConfig {
+            bandwidth: 1000,
+            queue_limit: Duration::from_millis(500),
+            corrupt: 1.0,
+            ..LinkConfig::default()
+        };
+        let now = Instant::now();
+        let mut link = Link::new(config, 3);
+        for _ in 0..10 {
+            link.send(now, addr(), &[0; 100]);
+        }
+
+        // 100 bytes at 1000 bytes a second is one packet every 100ms,
+        // the queue only holds half a second of them
+        assert_eq!(
+            received(&mut link, now + Duration::from_millis(250)).len(),
+            2
+        );
+        let rest = received(&mut link, now + Duration::from_secs(1));
+        assert_eq!(rest.len(), 4);
+        for data in rest {
+            assert_eq!(data.iter().map(|b| b.count_ones()).sum::<u32>(), 1);
+        }
+    }
+
+    #[test]
+    fn test_conditioner_transport() {
+        let a = "127.0.0.1:1000".parse().unwrap();
+        let b = "127.0.0.1:2000".parse().unwrap();
+        let (a_end, b_end) = MemoryTransport::pair(a, b);
+        let delayed = LinkConfig {
+            latency: Latency::Fixed(Duration::from_millis(20)),
+            ..LinkConfig::default()
+        };
+        let mut a_end = LinkConditioner::new(a_end, LinkConfig::default(), delayed, 0);
+        let mut b_end = LinkConditioner::new(b_end, delayed, LinkConfig::default(), 0);
+        let mut buf = [0; 16];
+
+        a_end.send_to(b"hi", b).unwrap();
+        assert!(b_end.recv_from(&mut buf).is_err());
+        std::thread::sleep(Duration::from_millis(25));
+        // Still held by the sender until it is polled
+        assert!(b_end.recv_from(&mut buf).is_err());
+        assert!(a_end.recv_from(&mut buf).is_err());
+        assert!(b_end.recv_from(&mut buf).is_err());
+        std::thread::sleep(Duration::from_millis(25));
+        assert_eq!(b_end.recv_from(&mut buf).unwrap(), (2, a));
+        assert_eq!(&buf[..2], b"hi");
+    }
+}
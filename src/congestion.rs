@@ -0,0 +1,197 @@
+use std::time::{Duration, Instant};
+
+use crate::rtt::RttEstimator;
+
+// Packets per second and payload bytes per packet in each mode
+const GOOD_RATE: u32 = 60;
+const GOOD_PAYLOAD: usize = 1200;
+const BAD_RATE: u32 = 20;
+// Still room for a whole fragment and its headers
+const BAD_PAYLOAD: usize = 1040;
+
+// Any of these mean the link is struggling
+const BAD_RTT: Duration = Duration::from_millis(250);
+const BAD_LOSS: f32 = 10.0;
+// RTT this far above the lowest seen means queues are building up
+// somewhere, which usually comes before loss
+const BAD_QUEUE_DELAY: Duration = Duration::from_millis(100);
+
+// How long conditions have to stay good before leaving bad mode. It
+// grows when good mode doesn't last and shrinks while it does.
+const INITIAL_RECOVER_TIME: Duration = Duration::from_secs(10);
+const MIN_RECOVER_TIME: Duration = Duration::from_secs(1);
+const MAX_RECOVER_TIME: Duration = Duration::from_secs(60);
+const STABLE_TIME: Duration = Duration::from_secs(10);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    Good,
+    Bad,
+}
+
+// Picks how fast and how much a connection sends, dropping to a lower
+// rate while RTT or loss say the link can't keep up
+#[derive(Clone, Debug)]
+pub struct CongestionControl {
+    mode: Mode,
+    mode_since: Instant,
+    good_since: Option<Instant>,
+    recover_time: Duration,
+    // Last time `recover_time` was shortened for staying in good mode
+    stable_since: Instant,
+    next_send_at: Instant,
+}
+
+impl CongestionControl {
+    pub fn new(now: Instant) -> Self {
+        CongestionControl {
+            mode: Mode::Good,
+            mode_since: now,
+            good_since: None,
+            recover_time: INITIAL_RECOVER_TIME,
+            stable_since: now,
+            next_send_at: now,
+        }
+    }
+
+    // `loss` is a percentage of recently sent packets
+    pub fn update(&mut self, now: Instant, rtt: &RttEstimator, loss: f32) {
+        let bad = rtt.latest().is_some()
+            && (rtt.smoothed() > BAD_RTT
+                || rtt.smoothed() > rtt.min() + BAD_QUEUE_DELAY
+                || loss > BAD_LOSS);
+
+        match self.mode {
+            Mode::Good if bad => {
+                // Good mode didn't last, be slower to trust it next time
+                if now - self.mode_since < STABLE_TIME {
+                    self.recover_time = (self.recover_time * 2).min(MAX_RECOVER_TIME);
+                }
+                self.mode = Mode::Bad;
+                self.mode_since = now;
+                self.good_since = None;
+            }
+            Mode::Good => {
+                if now - self.stable_since >= STABLE_TIME {
+                    self.recover_time = (self.recover_time / 2).max(MIN_RECOVER_TIME);
+                    self.stable_since = now;
+                }
+            }
+            Mode::Bad if bad => self.good_since = None,
+            Mode::Bad => {
+                let good_since = *self.good_since.get_or_insert(now);
+                if now - good_since >= self.recover_time {
+                    self.mode = Mode::Good;
+                    self.mode_since = now;
+                    self.stable_since = now;
+                }
+            }
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn send_rate(&self) -> u32 {
+        match self.mode {
+            Mode::Good => GOOD_RATE,
+            Mode::Bad => BAD_RATE,
+        }
+    }
+
+    pub fn payload_budget(&self) -> usize {
+        match self.mode {
+            Mode::Good => GOOD_PAYLOAD,
+            Mode::Bad => BAD_PAYLOAD,
+        }
+    }
+
+    // Bytes per second of payload
+    pub fn allowed_bandwidth(&self) -> f32 {
+        (self.send_rate() as usize * self.payload_budget()) as f32
+    }
+
+    pub fn send_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.send_rate()
+    }
+
+    // Callers tick at their own rate, so a send is allowed up to half an
+    // interval early to keep the average rate without drifting a tick
+    pub fn ready_to_send(&self, now: Instant) -> bool {
+        now + self.send_interval() / 2 >= self.next_send_at
+    }
+
+    // Earliest time `ready_to_send` is true
+    pub fn next_send_at(&self) -> Instant {
+        let early = self.send_interval() / 2;
+        self.next_send_at
+            .checked_sub(early)
+            .unwrap_or(self.next_send_at)
+    }
+
+    pub fn on_send(&mut self, now: Instant) {
+        let interval = self.send_interval();
+        // Don't let a long pause turn into a burst
+        self.next_send_at = if now > self.next_send_at + interval {
+            now + interval
+        } else {
+            self.next_send_at + interval
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_mode_switching() {
+        let start = Instant::now();
+        let mut control = CongestionControl::new(start);
+        let mut rtt = RttEstimator::new();
+        rtt.on_sample(ms(50));
+        control.update(start, &rtt, 0.0);
+        assert_eq!(control.mode(), Mode::Good);
+
+        control.update(start + ms(100), &rtt, 20.0);
+        assert_eq!(control.mode(), Mode::Bad);
+        assert!(control.allowed_bandwidth() < (GOOD_RATE as usize * GOOD_PAYLOAD) as f32);
+        // Dropped out of good mode straight away so recovery takes longer
+        assert_eq!(control.recover_time, INITIAL_RECOVER_TIME * 2);
+
+        let recovered = start + ms(200) + INITIAL_RECOVER_TIME * 2;
+        control.update(start + ms(200), &rtt, 0.0);
+        control.update(recovered - ms(1), &rtt, 0.0);
+        assert_eq!(control.mode(), Mode::Bad);
+        control.update(recovered, &rtt, 0.0);
+        assert_eq!(control.mode(), Mode::Good);
+
+        // Growing queues count as congestion before anything is lost
+        for _ in 0..20 {
+            rtt.on_sample(ms(200));
+        }
+        control.update(recovered + ms(100), &rtt, 0.0);
+        assert_eq!(control.mode(), Mode::Bad);
+    }
+
+    #[test]
+    fn test_pacing() {
+        let start = Instant::now();
+        let mut control = CongestionControl::new(start);
+        let mut sent = 0;
+        // Ticking at 16ms keeps close to 60 packets a second
+        for tick in 0..625 {
+            let now = start + ms(16 * tick);
+            if control.ready_to_send(now) {
+                control.on_send(now);
+                sent += 1;
+            }
+        }
+        assert!((590..=610).contains(&sent));
+    }
+}
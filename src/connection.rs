@@ -1,14 +1,61 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
-use crate::message_queue::MessageQueue;
-use crate::packet::Packet;
+use crate::channel::{
+    self, ChannelId, ChannelKind, MessageId, CHANNEL_HEADER_LENGTH, DEFAULT_CHANNELS,
+};
+use crate::congestion::{CongestionControl, Mode};
+use crate::crypto::{Keys, ReplayProtection};
+use crate::message_queue::{Delivery, MessageQueue, SendError};
+use crate::packet::{Acks, Challenge, ConnectionRequest, Packet, ParseError, Payload};
+use crate::rtt::RttEstimator;
+use crate::sequence::Sequence;
+use crate::sequence_buffer::SequenceBuffer;
+use crate::stats::{BandwidthMeter, ConnectionStats};
 
 const BUFFER_SIZE: usize = 128;
 
+// How long the handshake may take before giving up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Connection requests are resent this often until the server answers
+const CONNECT_RESEND_INTERVAL: Duration = Duration::from_millis(100);
+// How long a connected peer may stay silent before it is dropped
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+// Disconnects are unreliable so a few are sent before closing
+const DISCONNECT_PACKETS: u32 = 3;
+// Sent when nothing else has gone out for this long, so the peer
+// doesn't time us out while there is nothing to say
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(250);
+// How long received packets may wait for a payload to carry their acks
+// before the acks are sent on their own
+const ACK_DELAY: Duration = Duration::from_millis(30);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Challenged,
+    Connected,
+    Disconnecting,
+    Disconnected,
+}
+
+// Why the connection last changed state
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StateReason {
+    Created,
+    ChallengeReceived,
+    Accepted,
+    Denied,
+    ServerFull,
+    ConnectTimedOut,
+    TimedOut,
+    LocalDisconnect,
+    RemoteDisconnect,
+}
+
 #[derive(Copy, Clone, Debug)]
 struct PacketData {
-    seq: u16,
     sent_time: Instant,
 }
 
@@ -16,163 +63,980 @@ struct PacketData {
 enum PacketState {
     Acknowledged(PacketData),
     UnAcknowledged(PacketData),
+    Lost(PacketData),
+}
+
+// What became of a payload packet, by the sequence `send` returned
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PacketEvent {
+    Acked(Sequence),
+    // The peer can no longer ack it, either its acks have moved more
+    // than 32 packets past or it has dropped out of the ack buffer
+    Lost(Sequence),
 }
 
 pub struct Connection {
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
+    state: ConnectionState,
+    state_reason: StateReason,
+    state_changed_at: Instant,
+    token: Vec<u8>,
+    challenge: Vec<u8>,
+    disconnect_packets: u32,
+    keys: Option<Keys>,
+    // Backend's id for the client, from its connect token
+    user_id: Option<u64>,
+    // Nonce for the next sealed packet, separate from `sequence` as
+    // that wraps and a nonce must never be reused
+    sealed_sequence: u64,
+    replay_protection: ReplayProtection,
     last_received_at: Instant,
     last_sent_at: Instant,
-    sequence: u16,
-    last_received_sequence: u16,
-    recv_ack_buffer: [Option<u16>; BUFFER_SIZE],
-    sent_ack_buffer: [Option<PacketState>; BUFFER_SIZE],
-    message_queue: MessageQueue,
+    next_request_at: Instant,
+    sequence: Sequence,
+    last_received_sequence: Sequence,
+    // Oldest received packet that hasn't been acked back yet
+    ack_owed_since: Option<Instant>,
+    recv_ack_buffer: SequenceBuffer<()>,
+    sent_ack_buffer: SequenceBuffer<PacketState>,
+    channels: Vec<MessageQueue>,
+    // Datagrams waiting to be picked up by `poll_transmit`
+    outgoing: VecDeque<Vec<u8>>,
     recv_packets: u32,
     acked_packets: u32,
     lost_packets: u32,
+    packet_events: VecDeque<PacketEvent>,
     sent_packets: u32,
-    rtt: f32,
+    corrupt_packets: u32,
+    sent_bytes: BandwidthMeter,
+    recv_bytes: BandwidthMeter,
+    rtt: RttEstimator,
+    congestion: CongestionControl,
 }
 
 impl Connection {
-    pub fn new(local_addr: SocketAddr, remote_addr: SocketAddr) -> Connection {
+    pub fn new(local_addr: SocketAddr, remote_addr: SocketAddr, now: Instant) -> Connection {
+        Connection::with_channels(local_addr, remote_addr, &DEFAULT_CHANNELS, now)
+    }
+
+    // Channel ids are the index of the kind in `channels`, both
+    // ends need to be created with the same list
+    pub fn with_channels(
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+        channels: &[ChannelKind],
+        now: Instant,
+    ) -> Connection {
         Connection {
             local_addr,
             remote_addr,
-            last_received_at: Instant::now(),
-            last_sent_at: Instant::now(),
-            sequence: 0,
-            last_received_sequence: 0,
-            recv_ack_buffer: [None; BUFFER_SIZE],
-            sent_ack_buffer: [None; BUFFER_SIZE],
-            message_queue: MessageQueue::new(),
+            state: ConnectionState::Connecting,
+            state_reason: StateReason::Created,
+            state_changed_at: now,
+            token: Vec::new(),
+            challenge: Vec::new(),
+            disconnect_packets: 0,
+            keys: None,
+            user_id: None,
+            sealed_sequence: 0,
+            replay_protection: ReplayProtection::new(),
+            last_received_at: now,
+            last_sent_at: now,
+            next_request_at: now,
+            sequence: Sequence(0),
+            last_received_sequence: Sequence(0),
+            ack_owed_since: None,
+            recv_ack_buffer: SequenceBuffer::new(BUFFER_SIZE),
+            sent_ack_buffer: SequenceBuffer::new(BUFFER_SIZE),
+            channels: channels
+                .iter()
+                .map(|kind| MessageQueue::new(*kind))
+                .collect(),
+            outgoing: VecDeque::new(),
             recv_packets: 0,
             acked_packets: 0,
             lost_packets: 0,
+            packet_events: VecDeque::new(),
             sent_packets: 0,
-            rtt: 0.0,
+            corrupt_packets: 0,
+            sent_bytes: BandwidthMeter::new(),
+            recv_bytes: BandwidthMeter::new(),
+            rtt: RttEstimator::new(),
+            congestion: CongestionControl::new(now),
         }
     }
 
-    pub fn queue_message(&mut self, message: &[u8]) {
-        self.message_queue.queue_message(message);
+    pub fn queue_message(
+        &mut self,
+        channel: ChannelId,
+        message: &[u8],
+    ) -> Result<MessageId, SendError> {
+        self.queue(channel, message, None)
     }
 
-    pub fn send(&mut self, socket: &mut UdpSocket) -> Result<usize, std::io::Error> {
-        use PacketState::UnAcknowledged;
+    // Reliable messages still not acked at `deadline` stop being resent
+    // and are reported as given up
+    pub fn queue_message_with_deadline(
+        &mut self,
+        channel: ChannelId,
+        message: &[u8],
+        deadline: Instant,
+    ) -> Result<MessageId, SendError> {
+        self.queue(channel, message, Some(deadline))
+    }
 
-        // Set sent packer buffer to ack them when needed
-        let index = self.sequence as usize % BUFFER_SIZE;
+    fn queue(
+        &mut self,
+        channel: ChannelId,
+        message: &[u8],
+        deadline: Option<Instant>,
+    ) -> Result<MessageId, SendError> {
+        if self.state == ConnectionState::Disconnecting
+            || self.state == ConnectionState::Disconnected
+        {
+            return Err(SendError::NotConnected);
+        }
+        self.channels
+            .get_mut(channel as usize)
+            .ok_or(SendError::UnknownChannel)?
+            .queue_message(message, deadline)
+            .map(|sequence| MessageId { channel, sequence })
+    }
 
-        // if unacked packet exists at location sequence has wrapped
-        // round and packet has been lost. ttl is send_rate / BUFFER_SIZE
-        // so buffer of 128 with a 60pps means a ~470ms ttl
-        if let Some(UnAcknowledged(_lost_packet)) = self.sent_ack_buffer[index] {
-            self.lost_packets = self.lost_packets.wrapping_add(1);
+    // Slots taken on `channel`, producers should hold off as this nears
+    // `send_queue_capacity`. `None` if there is no such channel.
+    pub fn send_queue_len(&self, channel: ChannelId) -> Option<usize> {
+        self.channels
+            .get(channel as usize)
+            .map(|queue| queue.send_queue_len())
+    }
+
+    pub fn send_queue_capacity(&self, channel: ChannelId) -> Option<usize> {
+        self.channels
+            .get(channel as usize)
+            .map(|queue| queue.send_queue_capacity())
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn state_reason(&self) -> StateReason {
+        self.state_reason
+    }
+
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    // Packets per second the link can take right now
+    pub fn send_rate(&self) -> u32 {
+        self.congestion.send_rate()
+    }
+
+    // Bytes per second of payload the link can take right now
+    pub fn allowed_bandwidth(&self) -> f32 {
+        self.congestion.allowed_bandwidth()
+    }
+
+    // The link is struggling and the application should send less
+    pub fn congested(&self) -> bool {
+        self.congestion.mode() == Mode::Bad
+    }
+
+    // Whether it's time for the next packet at the current send rate,
+    // or for the next connection request while connecting
+    pub fn ready_to_send(&self, now: Instant) -> bool {
+        match self.state {
+            ConnectionState::Connecting | ConnectionState::Challenged => {
+                now >= self.next_request_at
+            }
+            _ => self.congestion.ready_to_send(now),
+        }
+    }
+
+    // Loss over the packets still in the ack buffer that have had long
+    // enough for their ack to come back
+    fn packet_loss(&self, now: Instant) -> f32 {
+        let rto = self.rtt.rto();
+        let mut settled = 0;
+        let mut unacked = 0;
+        for (_, state) in self.sent_ack_buffer.iter() {
+            let (data, acked) = match state {
+                PacketState::Acknowledged(data) => (data, true),
+                PacketState::UnAcknowledged(data) | PacketState::Lost(data) => (data, false),
+            };
+            if now - data.sent_time >= rto {
+                settled += 1;
+                if !acked {
+                    unacked += 1;
+                }
+            }
         }
+        if settled > 0 {
+            unacked as f32 / settled as f32 * 100.0
+        } else {
+            0.0
+        }
+    }
 
-        self.sent_ack_buffer[index] = Some(UnAcknowledged(PacketData {
-            seq: self.sequence,
-            sent_time: Instant::now(),
-        }));
+    pub fn stats(&self, now: Instant) -> ConnectionStats {
+        ConnectionStats {
+            sent_packets: self.sent_packets,
+            recv_packets: self.recv_packets,
+            acked_packets: self.acked_packets,
+            lost_packets: self.lost_packets,
+            corrupt_packets: self.corrupt_packets,
+            sent_bytes: self.sent_bytes.total(),
+            recv_bytes: self.recv_bytes.total(),
+            packet_loss: self.packet_loss(now),
+            rtt: self.rtt.smoothed(),
+            rtt_variance: self.rtt.variance(),
+            min_rtt: self.rtt.min(),
+            latest_rtt: self.rtt.latest(),
+            jitter: self.rtt.jitter(),
+            rto: self.rtt.rto(),
+            quality: self.rtt.quality(),
+            bandwidth_out: self.sent_bytes.rate(now),
+            bandwidth_in: self.recv_bytes.rate(now),
+            send_rate: self.send_rate(),
+            allowed_bandwidth: self.allowed_bandwidth(),
+            congested: self.congested(),
+            send_queue: self
+                .channels
+                .iter()
+                .map(|queue| queue.send_queue_len())
+                .sum(),
+            recv_queue: self
+                .channels
+                .iter()
+                .map(|queue| queue.recv_queue_len())
+                .sum(),
+            outgoing: self.outgoing.len(),
+        }
+    }
 
-        // Get last 32 received packets and add them to acks if they exist
-        let mut acks: Vec<u16> = Vec::with_capacity(32);
-        for i in 0..32 {
-            let seq = self.last_received_sequence.wrapping_sub(i);
-            let index = seq as usize % BUFFER_SIZE;
+    // Once set every packet apart from the handshake is encrypted, and
+    // packets that aren't are dropped. Keys must never be given to more
+    // than one connection, each starts its nonces over from 0.
+    pub fn set_keys(&mut self, keys: Keys) {
+        self.keys = Some(keys);
+    }
+
+    pub fn set_user_id(&mut self, user_id: u64) {
+        self.user_id = Some(user_id);
+    }
+
+    pub fn user_id(&self) -> Option<u64> {
+        self.user_id
+    }
 
-            if let Some(buffered) = self.recv_ack_buffer[index] {
-                if seq == buffered {
-                    acks.push(seq);
+    // Sent with every connection request, see `ConnectToken`
+    pub fn set_token(&mut self, token: Vec<u8>) {
+        self.token = token;
+    }
+
+    // Used by the server once a client has answered its challenge
+    pub fn accept(&mut self, now: Instant) {
+        self.set_state(ConnectionState::Connected, StateReason::Accepted, now);
+    }
+
+    pub fn disconnect(&mut self, now: Instant) {
+        match self.state {
+            ConnectionState::Connected => self.set_state(
+                ConnectionState::Disconnecting,
+                StateReason::LocalDisconnect,
+                now,
+            ),
+            ConnectionState::Connecting | ConnectionState::Challenged => self.set_state(
+                ConnectionState::Disconnected,
+                StateReason::LocalDisconnect,
+                now,
+            ),
+            ConnectionState::Disconnecting | ConnectionState::Disconnected => {}
+        }
+    }
+
+    // The earliest time `update` or `send` next has something to do: a
+    // timeout, a connection request, acks or a keepalive falling due, or
+    // the next paced send
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let deadlines = match self.state {
+            ConnectionState::Connecting | ConnectionState::Challenged => vec![
+                self.state_changed_at + CONNECT_TIMEOUT,
+                self.next_request_at,
+            ],
+            ConnectionState::Connected => {
+                let mut deadlines = vec![
+                    self.last_received_at + CONNECTION_TIMEOUT,
+                    self.last_sent_at + KEEPALIVE_INTERVAL,
+                    self.congestion.next_send_at(),
+                ];
+                if let Some(since) = self.ack_owed_since {
+                    deadlines.push(since + ACK_DELAY);
+                }
+                deadlines
+            }
+            // The rest of the disconnect packets
+            ConnectionState::Disconnecting => vec![self.congestion.next_send_at()],
+            ConnectionState::Disconnected => vec![],
+        };
+        deadlines.into_iter().min()
+    }
+
+    // Checks for timeouts and queues any acks or keepalive that are due,
+    // called whenever there is time between sends. `send` makes the same
+    // checks and its payload carries the acks, so isn't preceded by these.
+    pub fn update(&mut self, now: Instant) {
+        self.check_timeouts(now);
+        if self.state != ConnectionState::Connected {
+            return;
+        }
+        if let Some(since) = self.ack_owed_since {
+            if now - since >= ACK_DELAY {
+                self.send_acks(now);
+            }
+        }
+        if now - self.last_sent_at >= KEEPALIVE_INTERVAL {
+            let datagram = self.encode(Packet::Keepalive);
+            self.transmit(datagram, now);
+        }
+    }
+
+    fn check_timeouts(&mut self, now: Instant) {
+        match self.state {
+            ConnectionState::Connecting | ConnectionState::Challenged => {
+                if now - self.state_changed_at >= CONNECT_TIMEOUT {
+                    self.set_state(
+                        ConnectionState::Disconnected,
+                        StateReason::ConnectTimedOut,
+                        now,
+                    );
+                }
+            }
+            ConnectionState::Connected => {
+                if now - self.last_received_at >= CONNECTION_TIMEOUT {
+                    self.set_state(ConnectionState::Disconnected, StateReason::TimedOut, now);
+                }
+            }
+            ConnectionState::Disconnecting | ConnectionState::Disconnected => {}
+        }
+    }
+
+    fn set_state(&mut self, state: ConnectionState, reason: StateReason, now: Instant) {
+        self.state = state;
+        self.state_reason = reason;
+        self.state_changed_at = now;
+    }
+
+    // Queues the next datagram for `poll_transmit`. What gets sent depends
+    // on how far along the handshake we are, only connected connections
+    // send payloads. Returns the sequence of the payload, which comes back
+    // in a `PacketEvent` once it is acked or lost.
+    pub fn send(&mut self, now: Instant) -> Option<Sequence> {
+        self.check_timeouts(now);
+
+        let packet = match self.state {
+            // Echo back the challenge cookie once we have one
+            ConnectionState::Connecting | ConnectionState::Challenged => {
+                self.next_request_at = now + CONNECT_RESEND_INTERVAL;
+                Packet::ConnectionRequest(ConnectionRequest {
+                    token: self.token.clone(),
+                    cookie: self.challenge.clone(),
+                })
+            }
+            ConnectionState::Connected => {
+                let loss = self.packet_loss(now);
+                self.congestion.update(now, &self.rtt, loss);
+                return Some(self.send_payload(now));
+            }
+            ConnectionState::Disconnecting => {
+                self.disconnect_packets += 1;
+                if self.disconnect_packets >= DISCONNECT_PACKETS {
+                    let reason = self.state_reason;
+                    self.set_state(ConnectionState::Disconnected, reason, now);
                 }
+                Packet::Disconnect
             }
+            ConnectionState::Disconnected => return None,
+        };
+
+        let datagram = self.encode(packet);
+        self.transmit(datagram, now);
+        None
+    }
+
+    fn transmit(&mut self, datagram: Vec<u8>, now: Instant) {
+        self.sent_bytes.record(now, datagram.len());
+        self.outgoing.push_back(datagram);
+        self.last_sent_at = now;
+    }
+
+    // Next datagram to put on the wire to `remote_addr`
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.outgoing.pop_front()
+    }
+
+    fn encode(&mut self, packet: Packet) -> Vec<u8> {
+        match &self.keys {
+            Some(keys) if !packet.is_handshake() => {
+                let vec = packet.into_vec_sealed(self.sealed_sequence, &keys.send);
+                self.sealed_sequence += 1;
+                vec
+            }
+            _ => packet.into_vec(),
+        }
+    }
+
+    fn send_payload(&mut self, now: Instant) -> Sequence {
+        use PacketState::UnAcknowledged;
+
+        // if a packet a whole buffer back is still unacked it has been
+        // lost. ttl is BUFFER_SIZE / send_rate so buffer of 128 with a
+        // 60pps means a ~2s ttl
+        let evicted = self.sequence.wrapping_sub(BUFFER_SIZE as u16);
+        if let Some(UnAcknowledged(_lost_packet)) = self.sent_ack_buffer.remove(evicted) {
+            self.lost_packets = self.lost_packets.wrapping_add(1);
+            self.push_packet_event(PacketEvent::Lost(evicted));
         }
 
-        let data = self.message_queue.send_next(self.sequence, 1200);
-        let packet = Packet::new(self.sequence, self.last_received_sequence, acks, data);
+        // Set sent packer buffer to ack them when needed
+        self.sent_ack_buffer
+            .insert(self.sequence, UnAcknowledged(PacketData { sent_time: now }));
 
-        let sent = socket.send_to(&packet.into_vec(), &self.remote_addr)?;
+        let acks = self.recent_acks();
+        self.ack_owed_since = None;
 
-        self.sequence = self.sequence.wrapping_add(1);
+        // Multiplex each channel's messages into the payload, channels
+        // earlier in the list get first pick of the budget
+        let mut data = Vec::new();
+        let rto = self.rtt.rto();
+        let budget = self.congestion.payload_budget();
+        for (id, queue) in self.channels.iter_mut().enumerate() {
+            let remaining = budget.saturating_sub(data.len() + CHANNEL_HEADER_LENGTH);
+            let mut block = queue.send_next(self.sequence, remaining as u16, now, rto);
+            if !block.is_empty() {
+                channel::write_block(&mut data, id as ChannelId, &mut block);
+            }
+        }
+        let packet = Packet::Payload(Payload::new(
+            self.sequence,
+            self.last_received_sequence,
+            acks,
+            data,
+        ));
+
+        let datagram = self.encode(packet);
+        self.transmit(datagram, now);
+
+        let sequence = self.sequence;
+        self.sequence = self.sequence.next();
         self.sent_packets = self.sent_packets.wrapping_add(1);
-        self.last_sent_at = Instant::now();
+        self.congestion.on_send(now);
+        sequence
+    }
+
+    fn send_acks(&mut self, now: Instant) {
+        let packet = Packet::Ack(Acks::new(self.last_received_sequence, self.recent_acks()));
+        let datagram = self.encode(packet);
+        self.transmit(datagram, now);
+        self.ack_owed_since = None;
+    }
 
-        Ok(sent)
+    // Get last 32 received packets and add them to acks if they exist
+    fn recent_acks(&self) -> Vec<Sequence> {
+        let mut acks: Vec<Sequence> = Vec::with_capacity(32);
+        for i in 0..32 {
+            let seq = self.last_received_sequence.wrapping_sub(i);
+            if self.recv_ack_buffer.contains(seq) {
+                acks.push(seq);
+            }
+        }
+        acks
     }
 
-    pub fn receive_packet(&mut self, data: &[u8]) {
-        use PacketState::{Acknowledged, UnAcknowledged};
+    pub fn receive_packet(&mut self, data: &[u8], now: Instant) {
+        self.recv_bytes.record(now, data.len());
+        let packet = match &self.keys {
+            Some(keys) => Packet::open(data, &keys.recv, &mut self.replay_protection),
+            None => Packet::from_slice(data),
+        };
+        let packet = match packet {
+            Ok(packet) => packet,
+            Err(ParseError::ChecksumMismatch) => {
+                self.corrupt_packets = self.corrupt_packets.wrapping_add(1);
+                return;
+            }
+            Err(_) => return,
+        };
+
+        match (packet, self.state) {
+            (_, ConnectionState::Disconnected) => return,
+            (Packet::Challenge(Challenge { cookie }), ConnectionState::Connecting)
+            | (Packet::Challenge(Challenge { cookie }), ConnectionState::Challenged) => {
+                self.challenge = cookie;
+                // Answer straight away rather than at the next resend
+                self.next_request_at = now;
+                self.set_state(
+                    ConnectionState::Challenged,
+                    StateReason::ChallengeReceived,
+                    now,
+                );
+            }
+            (Packet::Denied, ConnectionState::Connecting)
+            | (Packet::Denied, ConnectionState::Challenged) => {
+                self.set_state(ConnectionState::Disconnected, StateReason::Denied, now);
+            }
+            (Packet::ServerFull, ConnectionState::Connecting)
+            | (Packet::ServerFull, ConnectionState::Challenged) => {
+                self.set_state(ConnectionState::Disconnected, StateReason::ServerFull, now);
+            }
+            (Packet::Disconnect, _) => {
+                self.set_state(
+                    ConnectionState::Disconnected,
+                    StateReason::RemoteDisconnect,
+                    now,
+                );
+            }
+            // The first packet after answering the challenge means we are in
+            (Packet::Payload(payload), ConnectionState::Challenged) => {
+                self.accept(now);
+                self.receive_payload(payload, now);
+            }
+            (Packet::Keepalive, ConnectionState::Challenged) => self.accept(now),
+            (Packet::Payload(payload), ConnectionState::Connected) => {
+                self.receive_payload(payload, now);
+            }
+            (Packet::Keepalive, ConnectionState::Connected) => {}
+            (Packet::Ack(acks), ConnectionState::Connected) => {
+                self.receive_acks(acks.ack, &acks.acks, now);
+            }
+            _ => return,
+        }
 
-        let packet = Packet::from_slice(data).unwrap();
+        // Update received at time
+        self.last_received_at = now;
+    }
+
+    fn receive_payload(&mut self, packet: Payload, now: Instant) {
+        // A duplicated datagram, its messages have already been taken
+        if self.recv_ack_buffer.contains(packet.sequence) {
+            return;
+        }
         self.recv_packets = self.recv_packets.wrapping_add(1);
+        self.rtt.on_arrival(now);
 
         // Update last received packet sequence number if it is within
         // window of half u16::MAX
-        if is_recent(packet.sequence, self.last_received_sequence) {
+        if packet.sequence > self.last_received_sequence {
             self.last_received_sequence = packet.sequence
         }
 
-        // Update received at time
-        self.last_received_at = Instant::now();
-
         // Buffer sequence number for sending back acks
-        let index = packet.sequence as usize % BUFFER_SIZE;
-        self.recv_ack_buffer[index] = Some(packet.sequence);
+        self.recv_ack_buffer.insert(packet.sequence, ());
+        self.ack_owed_since.get_or_insert(now);
 
-        // Receive messages into message queue
-        self.message_queue.recv_messages(&packet.data);
+        // Receive messages into their channel's queue
+        if let Ok(blocks) = channel::read_blocks(&packet.data) {
+            for (id, block) in blocks {
+                if let Some(queue) = self.channels.get_mut(id as usize) {
+                    queue.recv_messages(block);
+                }
+            }
+        }
+
+        self.receive_acks(packet.ack, &packet.acks, now);
+    }
 
-        // Confirm received acks
-        for seq in packet.acks.iter() {
-            let sn = *seq as usize;
-            let index = sn % BUFFER_SIZE;
+    // Confirm received acks, `ack` is the newest packet the peer has seen
+    fn receive_acks(&mut self, ack: Sequence, acks: &[Sequence], now: Instant) {
+        use PacketState::{Acknowledged, Lost, UnAcknowledged};
 
+        // Oldest first so events come out in the order packets were sent
+        for seq in acks.iter().rev() {
             // If we we have sent a packet and it is currently unacked
             // we need to set it to acked.
-            if let Some(UnAcknowledged(pdata)) = self.sent_ack_buffer[index] {
-                self.sent_ack_buffer[index] = Some(Acknowledged(pdata));
-                self.acked_packets = self.acked_packets.wrapping_add(1);
+            if let Some(state) = self.sent_ack_buffer.get_mut(*seq) {
+                if let UnAcknowledged(pdata) = *state {
+                    *state = Acknowledged(pdata);
+                    self.acked_packets = self.acked_packets.wrapping_add(1);
 
-                // Ack the message queue
-                self.message_queue.acknowledge(pdata.seq);
+                    // Ack the message queues
+                    for queue in self.channels.iter_mut() {
+                        queue.acknowledge(*seq, now);
+                    }
 
-                self.rtt = smoothed_average(self.rtt, self.last_received_at - pdata.sent_time);
-            };
+                    self.rtt.on_sample(now - pdata.sent_time);
+                    self.push_packet_event(PacketEvent::Acked(*seq));
+                }
+            }
+        }
+
+        // Anything still unacked from before the peer's ack window won't
+        // be acked now, unless the peer hasn't actually seen `ack` yet
+        if !acks.contains(&ack) {
+            return;
+        }
+        let window_start = ack.wrapping_sub(31);
+        let mut lost = Vec::new();
+        for (seq, state) in self.sent_ack_buffer.iter() {
+            if let UnAcknowledged(_) = state {
+                if seq < window_start {
+                    lost.push(seq);
+                }
+            }
+        }
+        lost.sort();
+        for seq in lost {
+            if let Some(state) = self.sent_ack_buffer.get_mut(seq) {
+                if let UnAcknowledged(pdata) = *state {
+                    *state = Lost(pdata);
+                }
+            }
+            self.lost_packets = self.lost_packets.wrapping_add(1);
+            self.push_packet_event(PacketEvent::Lost(seq));
+        }
+    }
+
+    // Acks and losses of sent payloads since the last call. Only events
+    // for the last BUFFER_SIZE packets sent are kept.
+    pub fn packet_events(&mut self) -> impl Iterator<Item = PacketEvent> + '_ {
+        self.packet_events.drain(..)
+    }
+
+    fn push_packet_event(&mut self, event: PacketEvent) {
+        if self.packet_events.len() == BUFFER_SIZE {
+            self.packet_events.pop_front();
         }
+        self.packet_events.push_back(event);
     }
 
-    pub fn recv_messages(&mut self) -> Vec<Vec<u8>> {
-        self.message_queue.recv_next_all()
+    pub fn recv_messages(&mut self) -> Vec<(ChannelId, Vec<u8>)> {
+        let mut messages = Vec::new();
+        for (id, queue) in self.channels.iter_mut().enumerate() {
+            for message in queue.recv_next_all() {
+                messages.push((id as ChannelId, message));
+            }
+        }
+        messages
+    }
+
+    // What became of reliable messages since the last call, unreliable
+    // ones are never reported
+    pub fn deliveries(&mut self) -> Vec<(MessageId, Delivery)> {
+        let mut deliveries = Vec::new();
+        for (id, queue) in self.channels.iter_mut().enumerate() {
+            for (sequence, delivery) in queue.deliveries() {
+                let channel = id as ChannelId;
+                deliveries.push((MessageId { channel, sequence }, delivery));
+            }
+        }
+        deliveries
     }
 }
 
-impl Drop for Connection {
-    fn drop(&mut self) {
-        println!(
-            "sent {}\nrecv {}\nacked {}\nlost {}\nrecent_recv {}\npacket rtt {}ms\n",
-            self.sent_packets,
-            self.recv_packets,
-            self.acked_packets,
-            self.lost_packets,
-            self.last_received_sequence,
-            self.rtt,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Challenge;
+
+    fn pair(now: Instant) -> (Connection, Connection) {
+        let client_addr = "127.0.0.1:1000".parse().unwrap();
+        let server_addr = "127.0.0.1:2000".parse().unwrap();
+        let mut client = Connection::new(client_addr, server_addr, now);
+        let mut server = Connection::new(server_addr, client_addr, now);
+
+        // Stand in for the server's stateless side of the handshake
+        client.send(now);
+        assert!(client.poll_transmit().is_some());
+        let challenge = Packet::Challenge(Challenge { cookie: vec![1] });
+        client.receive_packet(&challenge.into_vec(), now);
+        assert_eq!(client.state(), ConnectionState::Challenged);
+
+        server.accept(now);
+        (client, server)
+    }
+
+    fn deliver(from: &mut Connection, to: &mut Connection, now: Instant) {
+        from.send(now);
+        while let Some(datagram) = from.poll_transmit() {
+            to.receive_packet(&datagram, now);
+        }
+    }
+
+    #[test]
+    fn test_handshake_and_messages() {
+        let now = Instant::now();
+        let (mut client, mut server) = pair(now);
+
+        server.queue_message(0, b"hello").unwrap();
+        deliver(&mut server, &mut client, now);
+        assert_eq!(client.state(), ConnectionState::Connected);
+        assert_eq!(client.recv_messages(), vec![(0, b"hello".to_vec())]);
+
+        client.queue_message(1, b"hi").unwrap();
+        deliver(&mut client, &mut server, now);
+        assert_eq!(server.recv_messages(), vec![(1, b"hi".to_vec())]);
+    }
+
+    #[test]
+    fn test_unknown_channel() {
+        let now = Instant::now();
+        let (mut client, _server) = pair(now);
+        let missing = DEFAULT_CHANNELS.len() as ChannelId;
+
+        assert_eq!(
+            client.queue_message(missing, b"lost"),
+            Err(SendError::UnknownChannel)
         );
+        assert_eq!(client.send_queue_len(missing), None);
+        assert_eq!(client.send_queue_capacity(missing), None);
+        assert_eq!(client.send_queue_len(0), Some(0));
     }
-}
 
-// Static Helpers
+    #[test]
+    fn test_sequence_wrap() {
+        let now = Instant::now();
+        let (mut client, mut server) = pair(now);
+        server.sequence = Sequence(u16::MAX - 20);
+        client.sequence = Sequence(u16::MAX - 20);
 
-fn is_recent(new: u16, old: u16) -> bool {
-    if new > old {
-        (new - old) <= 32768
-    } else {
-        (old - new) > 32768
+        for i in 0..40u8 {
+            server.queue_message(0, &[i]).unwrap();
+            deliver(&mut server, &mut client, now);
+            deliver(&mut client, &mut server, now);
+        }
+        assert_eq!(server.sequence, Sequence(19));
+        let messages: Vec<_> = (0..40).map(|i| (0, vec![i])).collect();
+        assert_eq!(client.recv_messages(), messages);
+
+        // Every packet either side of the wrap was acked, the last one
+        // each way only once it has had a reply
+        assert_eq!(server.stats(now).acked_packets, 40);
+        assert_eq!(client.stats(now).acked_packets, 39);
+        assert_eq!(server.stats(now).send_queue, 0);
     }
-}
 
-fn smoothed_average(curr: f32, b: Duration) -> f32 {
-    let av = (b.as_secs() as f32) * 1000.0 + b.subsec_millis() as f32;
-    (curr - (curr - av) * 0.1).max(0.0)
+    #[test]
+    fn test_packet_events() {
+        let now = Instant::now();
+        let (mut client, mut server) = pair(now);
+        deliver(&mut server, &mut client, now);
+
+        // The middle packet is dropped
+        let mut sent = Vec::new();
+        for _ in 0..3 {
+            sent.push(server.send(now).unwrap());
+        }
+        assert_eq!(sent, vec![Sequence(1), Sequence(2), Sequence(3)]);
+        let datagrams: Vec<_> = server.outgoing.drain(..).collect();
+        client.receive_packet(&datagrams[0], now);
+        client.receive_packet(&datagrams[2], now);
+        deliver(&mut client, &mut server, now);
+        assert_eq!(
+            server.packet_events().collect::<Vec<_>>(),
+            vec![
+                PacketEvent::Acked(Sequence(0)),
+                PacketEvent::Acked(Sequence(1)),
+                PacketEvent::Acked(Sequence(3))
+            ]
+        );
+
+        // It is only given up on once the acks have moved past it
+        for _ in 0..30 {
+            deliver(&mut server, &mut client, now);
+        }
+        deliver(&mut client, &mut server, now);
+        assert!(!server
+            .packet_events()
+            .any(|event| event == PacketEvent::Lost(Sequence(2))));
+        deliver(&mut server, &mut client, now);
+        deliver(&mut client, &mut server, now);
+        assert_eq!(
+            server.packet_events().collect::<Vec<_>>(),
+            vec![
+                PacketEvent::Acked(Sequence(34)),
+                PacketEvent::Lost(Sequence(2))
+            ]
+        );
+        assert_eq!(server.stats(now).lost_packets, 1);
+
+        // Nothing is sent while connecting so there is nothing to track
+        let client_addr = "127.0.0.1:1000".parse().unwrap();
+        let server_addr = "127.0.0.1:2000".parse().unwrap();
+        let mut connecting = Connection::new(client_addr, server_addr, now);
+        assert_eq!(connecting.send(now), None);
+    }
+
+    #[test]
+    fn test_stats() {
+        let now = Instant::now();
+        let (mut client, mut server) = pair(now);
+        let later = now + Duration::from_millis(40);
+        for _ in 0..10 {
+            deliver(&mut server, &mut client, now);
+        }
+        let handshake_bytes = client.stats(now).sent_bytes;
+        deliver(&mut client, &mut server, later);
+
+        let stats = server.stats(later);
+        assert_eq!(stats.sent_packets, 10);
+        assert_eq!(stats.acked_packets, 10);
+        assert_eq!(
+            stats.recv_bytes,
+            client.stats(later).sent_bytes - handshake_bytes
+        );
+        assert_eq!(stats.latest_rtt, Some(Duration::from_millis(40)));
+        assert!(stats.bandwidth_out > 0.0);
+
+        // Nothing the client sent has been acked
+        let stats = client.stats(later + Duration::from_secs(2));
+        assert_eq!(stats.packet_loss, 100.0);
+        assert_eq!(stats.bandwidth_in, 0.0);
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let now = Instant::now();
+        let (mut client, mut server) = pair(now);
+        deliver(&mut server, &mut client, now);
+
+        // The first payload is due straight away, then the next at the
+        // send rate
+        assert!(client.poll_timeout().unwrap() <= now);
+        client.send(now);
+        let interval = client.congestion.send_interval();
+        assert_eq!(client.poll_timeout(), Some(now + interval - interval / 2));
+
+        // A driver that only wakes up when asked to keeps sending until
+        // the silent peer is timed out
+        let drive = |conn: &mut Connection| {
+            let mut sent = 0;
+            let mut last = now;
+            while let Some(deadline) = conn.poll_timeout() {
+                assert!(deadline >= last);
+                last = deadline;
+                if conn.ready_to_send(deadline) {
+                    conn.send(deadline);
+                } else {
+                    conn.update(deadline);
+                }
+                while conn.poll_transmit().is_some() {
+                    sent += 1;
+                }
+            }
+            (sent, last)
+        };
+        let (sent, last) = drive(&mut client);
+        assert_eq!(client.state_reason(), StateReason::TimedOut);
+        assert_eq!(last, now + CONNECTION_TIMEOUT);
+        assert!(sent > 500);
+
+        // Connection requests go out on time too
+        let client_addr = "127.0.0.1:1000".parse().unwrap();
+        let server_addr = "127.0.0.1:2000".parse().unwrap();
+        let mut connecting = Connection::new(client_addr, server_addr, now);
+        let (sent, last) = drive(&mut connecting);
+        assert_eq!(connecting.state_reason(), StateReason::ConnectTimedOut);
+        assert_eq!(last, now + CONNECT_TIMEOUT);
+        assert!((49..=51).contains(&sent));
+    }
+
+    #[test]
+    fn test_acks_and_keepalives() {
+        let now = Instant::now();
+        let (mut client, mut server) = pair(now);
+        deliver(&mut server, &mut client, now);
+
+        // The ack waits a little for a payload to carry it
+        client.update(now + ACK_DELAY / 2);
+        assert!(client.poll_transmit().is_none());
+        let later = now + ACK_DELAY;
+        client.update(later);
+        let datagram = client.poll_transmit().unwrap();
+        assert!(client.poll_transmit().is_none());
+        server.receive_packet(&datagram, later);
+        assert_eq!(server.stats(later).acked_packets, 1);
+        assert_eq!(server.stats(later).latest_rtt, Some(ACK_DELAY));
+
+        // Nothing is owed once the acks have gone out
+        client.update(later + ACK_DELAY);
+        assert!(client.poll_transmit().is_none());
+
+        // Then an idle link sends keepalives, keeping the peer from timing out
+        let idle = later + KEEPALIVE_INTERVAL;
+        client.update(idle);
+        let keepalive = client.poll_transmit().unwrap();
+        server.receive_packet(&keepalive, idle);
+        assert_eq!(server.last_received_at, idle);
+        assert_eq!(server.stats(idle).recv_packets, 0);
+
+        // A payload after a long idle spell is all that goes out, it
+        // carries the owed acks itself
+        deliver(&mut server, &mut client, idle);
+        let quiet = idle + KEEPALIVE_INTERVAL * 2;
+        client.queue_message(0, b"back").unwrap();
+        assert!(client.ready_to_send(quiet));
+        client.send(quiet);
+        let datagram = client.poll_transmit().unwrap();
+        assert!(client.poll_transmit().is_none());
+        server.receive_packet(&datagram, quiet);
+        assert_eq!(server.recv_messages(), vec![(0, b"back".to_vec())]);
+    }
+
+    #[test]
+    fn test_connect_requests() {
+        let now = Instant::now();
+        let client_addr = "127.0.0.1:1000".parse().unwrap();
+        let server_addr = "127.0.0.1:2000".parse().unwrap();
+        let mut client = Connection::new(client_addr, server_addr, now);
+        assert!(client.ready_to_send(now));
+        client.send(now);
+        assert!(client.poll_transmit().is_some());
+
+        // Requests go out at the resend interval, not the send rate
+        assert!(!client.ready_to_send(now + CONNECT_RESEND_INTERVAL / 2));
+        assert!(client.ready_to_send(now + CONNECT_RESEND_INTERVAL));
+
+        let later = now + CONNECT_RESEND_INTERVAL / 2;
+        let challenge = Packet::Challenge(Challenge { cookie: vec![1] });
+        client.receive_packet(&challenge.into_vec(), later);
+        assert!(client.ready_to_send(later));
+
+        client.receive_packet(&Packet::ServerFull.into_vec(), later);
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+        assert_eq!(client.state_reason(), StateReason::ServerFull);
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let now = Instant::now();
+        let (mut client, mut server) = pair(now);
+        deliver(&mut server, &mut client, now);
+
+        client.disconnect(now);
+        assert_eq!(client.state(), ConnectionState::Disconnecting);
+        assert_eq!(
+            client.queue_message(0, b"late"),
+            Err(SendError::NotConnected)
+        );
+        deliver(&mut client, &mut server, now);
+        assert_eq!(server.state(), ConnectionState::Disconnected);
+        assert_eq!(server.state_reason(), StateReason::RemoteDisconnect);
+
+        for _ in 1..DISCONNECT_PACKETS {
+            client.send(now);
+        }
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+        assert_eq!(client.state_reason(), StateReason::LocalDisconnect);
+    }
 }
@@ -0,0 +1,145 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::prelude::*;
+
+pub const KEY_SIZE: usize = 32;
+pub const MAC_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+// How far behind the newest sequence a packet may arrive
+const REPLAY_WINDOW: usize = 256;
+
+pub type Key = [u8; KEY_SIZE];
+
+// One key per direction so a packet can't be reflected back at its
+// sender. The other end uses the same keys the other way round.
+#[derive(Clone)]
+pub struct Keys {
+    pub send: Key,
+    pub recv: Key,
+}
+
+impl Keys {
+    pub fn generate() -> Self {
+        let mut rng = thread_rng();
+        Keys {
+            send: rng.gen(),
+            recv: rng.gen(),
+        }
+    }
+
+    // Keys for the other end of the connection
+    pub fn reversed(&self) -> Self {
+        Keys {
+            send: self.recv,
+            recv: self.send,
+        }
+    }
+}
+
+pub fn seal(key: &Key, sequence: u64, associated: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let payload = Payload {
+        msg: plaintext,
+        aad: associated,
+    };
+    cipher
+        .encrypt(&nonce(sequence).into(), payload)
+        .expect("plaintext fits in a packet")
+}
+
+pub fn open(key: &Key, sequence: u64, associated: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let payload = Payload {
+        msg: ciphertext,
+        aad: associated,
+    };
+    cipher.decrypt(&nonce(sequence).into(), payload).ok()
+}
+
+// Sequences are never reused with the same key, so they make a safe nonce
+fn nonce(sequence: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[4..].copy_from_slice(&sequence.to_be_bytes());
+    nonce
+}
+
+// Remembers recently received sequences so captured packets can't be
+// played back at us
+pub struct ReplayProtection {
+    most_recent: u64,
+    received: [Option<u64>; REPLAY_WINDOW],
+}
+
+impl ReplayProtection {
+    pub fn new() -> Self {
+        ReplayProtection {
+            most_recent: 0,
+            received: [None; REPLAY_WINDOW],
+        }
+    }
+
+    // Called before the packet is authenticated, so `sequence` can be
+    // anything at all
+    pub fn already_received(&self, sequence: u64) -> bool {
+        if sequence.saturating_add(REPLAY_WINDOW as u64) <= self.most_recent {
+            return true;
+        }
+        match self.received[sequence as usize % REPLAY_WINDOW] {
+            Some(received) => received >= sequence,
+            None => false,
+        }
+    }
+
+    // Only call once the packet has been authenticated
+    pub fn advance(&mut self, sequence: u64) {
+        if sequence > self.most_recent {
+            self.most_recent = sequence;
+        }
+        self.received[sequence as usize % REPLAY_WINDOW] = Some(sequence);
+    }
+}
+
+impl Default for ReplayProtection {
+    fn default() -> Self {
+        ReplayProtection::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let keys = Keys::generate();
+        let sealed = seal(&keys.send, 7, b"header", b"hello");
+        assert_eq!(sealed.len(), 5 + MAC_SIZE);
+        assert_eq!(
+            open(&keys.send, 7, b"header", &sealed),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(open(&keys.send, 8, b"header", &sealed), None);
+        assert_eq!(open(&keys.send, 7, b"headex", &sealed), None);
+        assert_eq!(open(&keys.recv, 7, b"header", &sealed), None);
+    }
+
+    #[test]
+    fn test_replay_protection() {
+        let mut replay = ReplayProtection::new();
+        assert!(!replay.already_received(0));
+        replay.advance(0);
+        assert!(replay.already_received(0));
+
+        replay.advance(300);
+        assert!(replay.already_received(300));
+        assert!(replay.already_received(10));
+        assert!(!replay.already_received(299));
+        assert!(!replay.already_received(301));
+
+        // Forged sequences at the top of the range mustn't overflow
+        assert!(!replay.already_received(u64::MAX));
+        replay.advance(u64::MAX);
+        assert!(replay.already_received(u64::MAX));
+        assert!(replay.already_received(300));
+    }
+}
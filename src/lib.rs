@@ -0,0 +1,17 @@
+pub mod challenge;
+pub mod channel;
+pub mod client;
+pub mod conditioner;
+pub mod congestion;
+pub mod connection;
+pub mod crypto;
+pub mod message_queue;
+pub mod packet;
+pub mod rtt;
+pub mod sequence;
+pub mod sequence_buffer;
+pub mod server;
+pub mod simulator;
+pub mod stats;
+pub mod token;
+pub mod transport;
@@ -1,22 +1,83 @@
+use std::net::UdpSocket;
 use std::thread;
 use std::time;
 
-mod client;
-mod connection;
-mod message_queue;
-mod packet;
-mod server;
-
-use client::Client;
-use server::Server;
+use networking::client::{Client, ClientEvent};
+use networking::conditioner::{BurstLoss, LinkConditioner, LinkConfig};
+use networking::connection::ConnectionState;
+use networking::crypto::Keys;
+use networking::server::{Server, ServerEvent};
+use networking::token::ConnectToken;
 
 fn main() {
     let client_addr = "0.0.0.0:12345".parse().unwrap();
     let server_addr = "127.0.0.1:12346".parse().unwrap();
 
+    // Stands in for the matchmaking backend
+    let token_key = Keys::generate().send;
+    let token = ConnectToken::generate(
+        &token_key,
+        1,
+        vec![server_addr],
+        time::Duration::from_secs(30),
+        time::SystemTime::now(),
+    );
+
     let j1 = thread::spawn(move || {
-        let mut server = Server::new(server_addr, 1504, 1).expect("bind fail");
-        server.run();
+        let socket = UdpSocket::bind(server_addr).expect("bind fail");
+        socket.set_nonblocking(true).unwrap();
+        // Drop a tenth of what the server receives
+        let lossy = LinkConfig {
+            loss: BurstLoss::uniform(0.1),
+            ..LinkConfig::default()
+        };
+        let socket = LinkConditioner::new(socket, lossy, LinkConfig::default(), 0);
+        let mut server = Server::with_transport(socket, 1504, 1).expect("bind fail");
+        server.require_tokens(token_key, server_addr);
+
+        let run_time = 19;
+        let pps = 60;
+        let start = time::Instant::now();
+        let mut last_sent = start;
+        let mut count = 0;
+        loop {
+            let now = time::Instant::now();
+            if now - start > time::Duration::from_secs(run_time) {
+                for client in server.clients() {
+                    println!("{}: {:?}", client, server.stats(client, now));
+                }
+                println!(
+                    "end, {} corrupt packets from unknown addresses, {} failed sends",
+                    server.corrupt_packets(),
+                    server.send_errors()
+                );
+                break;
+            }
+
+            if now - last_sent > time::Duration::from_millis(1000 / pps) {
+                server.broadcast(0, format!("ping:{}", count).as_bytes());
+                count += 1;
+                last_sent = now;
+            }
+
+            server.update(now).unwrap();
+            for event in server.events() {
+                match event {
+                    ServerEvent::ClientConnected(client) => println!("client {} connected", client),
+                    ServerEvent::ClientDisconnected(client, reason) => {
+                        println!("client {} disconnected: {:?}", client, reason)
+                    }
+                    ServerEvent::Message(_client, channel, msg) => {
+                        println!("[{}] {}", channel, std::str::from_utf8(&msg).unwrap())
+                    }
+                    ServerEvent::MessageAcked(..)
+                    | ServerEvent::MessageGivenUp(..)
+                    | ServerEvent::PacketSent(..)
+                    | ServerEvent::PacketAcked(..)
+                    | ServerEvent::PacketLost(..) => {}
+                }
+            }
+        }
     });
 
     let j2 = thread::spawn(move || {
@@ -25,33 +86,60 @@ fn main() {
 
         let mut client = Client::new(client_addr);
         client
-            .connect(server_addr)
+            .connect_with_token(token)
             .expect("Couldn't connect to server");
-        client.queue_message(b"connect".to_vec());
+        client.queue_message(0, b"connect".to_vec()).unwrap();
         client.send_next().unwrap();
         let start = time::Instant::now();
         let mut last_sent = time::Instant::now();
         let mut count: u128 = 0;
+        let mut skipped = 0;
         loop {
             let ltime = time::Instant::now();
             if time::Instant::now() - start > time::Duration::from_secs(run_time) {
+                client.disconnect();
+                while let Some((ConnectionState::Disconnecting, _)) = client.state() {
+                    client.send_next().unwrap();
+                }
+                println!("client: {:?}", client.state());
+                println!("client: {:?}", client.stats());
                 break;
             }
 
             if ltime - last_sent > time::Duration::from_millis(1000 / pps) {
-                client.queue_message(Vec::from(format!("pong:{}", count)));
+                // Fall back to a third of the updates while congested
+                if client.congested() && skipped < 2 {
+                    skipped += 1;
+                } else {
+                    skipped = 0;
+                    let pong = Vec::from(format!("pong:{}", count));
+                    if let Err(err) = client.queue_message(0, pong) {
+                        println!("client: pong {} not sent: {:?}", count, err);
+                    }
+                }
                 count += 1;
                 last_sent = ltime;
                 if let Ok(_amt) = client.send_next() {}
             }
 
-            if let Err(err) = client.recv() {
+            for event in client.events() {
+                match event {
+                    ClientEvent::MessageAcked(..)
+                    | ClientEvent::MessageGivenUp(..)
+                    | ClientEvent::PacketSent(_)
+                    | ClientEvent::PacketAcked(_)
+                    | ClientEvent::PacketLost(_) => {}
+                    event => println!("client: {:?}", event),
+                }
+            }
+
+            if client.recv().is_err() {
                 continue;
-            };
+            }
 
             if let Some(data) = client.recv_messages() {
-                for msg in data.iter() {
-                    println!("{}", std::str::from_utf8(&msg).unwrap());
+                for (channel, msg) in data.iter() {
+                    println!("[{}] {}", channel, std::str::from_utf8(msg).unwrap());
                 }
             }
         }
@@ -1,15 +1,118 @@
 use std::cmp;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use std::time::{Duration, Instant};
 
+use crate::channel::ChannelKind;
+use crate::sequence::Sequence;
+use crate::sequence_buffer::SequenceBuffer;
+
 const MESSAGE_HEADER_LENGTH: usize = 4;
-const BUFFER_SIZE: usize = 256;
+// Fragment index + fragment count, follows the message header
+const FRAGMENT_HEADER_LENGTH: usize = 4;
+// Set in the size field of fragment messages
+const FRAGMENT_FLAG: u16 = 0x8000;
+// Set in the size field of messages the sender gave up on, they carry
+// no data and only keep their id from holding up the ones after it
+const GIVEN_UP_FLAG: u16 = 0x4000;
+const BUFFER_SIZE: usize = 1024;
+// Packets whose messages are still waiting on an ack, the same as the
+// connection keeps track of
+const ACK_BUFFER_SIZE: usize = 128;
+// Due resends get the first 1/RESEND_SHARE of a packet, ahead of new
+// messages. Any more go in whatever room new messages leave.
+const RESEND_SHARE: u16 = 4;
+
+// Largest message that is sent whole, anything bigger is split up.
+// Leaves room for the packet, channel and message headers in 1200 bytes.
+pub const FRAGMENT_SIZE: usize = 1024;
+pub const MAX_FRAGMENTS: usize = BUFFER_SIZE / 2;
+pub const MAX_MESSAGE_SIZE: usize = FRAGMENT_SIZE * MAX_FRAGMENTS;
 
 #[derive(Eq, PartialEq, Clone)]
 struct Message {
-    id: u16,
+    id: Sequence,
     size: u16,
+    // (index, count) when this is one part of a larger message
+    fragment: Option<(u16, u16)>,
     data: Vec<u8>,
+    // None until the message has gone out in a packet
+    last_sent: Option<Instant>,
+    // Reliable messages stop being resent after this
+    deadline: Option<Instant>,
+    given_up: bool,
+}
+
+impl Message {
+    fn new(id: Sequence, fragment: Option<(u16, u16)>, data: Vec<u8>) -> Message {
+        Message {
+            id,
+            size: data.len() as u16,
+            fragment,
+            data,
+            last_sent: None,
+            deadline: None,
+            given_up: false,
+        }
+    }
+
+    // Id of the first fragment, which stands for the whole message
+    fn first(&self) -> Sequence {
+        match self.fragment {
+            Some((index, _)) => self.id.wrapping_sub(index),
+            None => self.id,
+        }
+    }
+
+    // What's left still goes out reliably, empty, so the receiver can
+    // move past its id
+    fn give_up(&mut self) {
+        self.data.clear();
+        self.size = 0;
+        self.deadline = None;
+        self.given_up = true;
+    }
+
+    fn encoded_len(&self) -> u16 {
+        let header = match self.fragment {
+            Some(_) => MESSAGE_HEADER_LENGTH + FRAGMENT_HEADER_LENGTH,
+            None => MESSAGE_HEADER_LENGTH,
+        };
+        header as u16 + self.size
+    }
+}
+
+// Fragments of a single message waiting for the rest to arrive
+struct Reassembly {
+    received: u16,
+    parts: Vec<Option<Vec<u8>>>,
+}
+
+// What became of a reliable message
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Delivery {
+    // Every part was acked, this long after the message first went out
+    Acked(Duration),
+    // Its deadline passed first
+    GivenUp,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SendError {
+    // Bigger than the channel can send
+    TooLarge,
+    // Every slot holds a message that is waiting to go out or on an ack,
+    // try again once `send_queue_len` has come down
+    QueueFull,
+    // Nowhere to send it yet, or any more
+    NotConnected,
+    // The connection has no channel with that id
+    UnknownChannel,
+}
+
+// A reliable message that isn't fully acked yet
+struct Pending {
+    fragments_left: u16,
+    first_sent: Option<Instant>,
 }
 
 impl Ord for Message {
@@ -25,78 +128,278 @@ impl PartialOrd for Message {
 }
 
 pub struct MessageQueue {
-    sequence_local: u16,
-    recent_acked: u16,
-    sequence_remote: u16,
-    awaiting_ack: HashMap<u16, Vec<u16>>,
-    send_queue: Vec<Option<Message>>,
+    kind: ChannelKind,
+    sequence_local: Sequence,
+    recent_acked: Sequence,
+    sequence_remote: Sequence,
+    // Message ids sent in each packet
+    awaiting_ack: SequenceBuffer<Vec<Sequence>>,
+    send_queue: SequenceBuffer<Message>,
+    // Keyed by the id returned from `queue_message`. Not a sequence
+    // buffer, a big message's first id can be a whole buffer behind the
+    // newest one while its last fragment waits on an ack.
+    pending: HashMap<Sequence, Pending>,
+    deliveries: Vec<(Sequence, Delivery)>,
+    unreliable_queue: VecDeque<Message>,
     recv_queue: BinaryHeap<Message>,
+    // Ids already delivered on unordered channels, or waiting in
+    // `recv_queue` on ordered ones
+    recv_ids: SequenceBuffer<()>,
+    // At most MAX_FRAGMENTS messages, all within BUFFER_SIZE ids
+    fragments: HashMap<Sequence, Reassembly>,
     recv: Vec<Vec<u8>>,
 }
 
-// message rtt
-// adjust message sent based on rtt
 impl MessageQueue {
-    pub fn new() -> Self {
+    pub fn new(kind: ChannelKind) -> Self {
         MessageQueue {
-            sequence_local: 0,
-            recent_acked: 0,
-            sequence_remote: 0,
-            awaiting_ack: HashMap::new(),
-            send_queue: vec![None; BUFFER_SIZE],
+            kind,
+            sequence_local: Sequence(0),
+            recent_acked: Sequence(0),
+            sequence_remote: Sequence(0),
+            awaiting_ack: SequenceBuffer::new(ACK_BUFFER_SIZE),
+            send_queue: SequenceBuffer::new(BUFFER_SIZE),
+            pending: HashMap::new(),
+            deliveries: Vec::new(),
+            unreliable_queue: VecDeque::new(),
             recv_queue: BinaryHeap::new(),
+            recv_ids: SequenceBuffer::new(BUFFER_SIZE),
+            fragments: HashMap::new(),
             recv: Vec::new(),
         }
     }
 
     // Sending -- Queue message -> get to send -> acknowledge pack id when acked
-    pub fn queue_message(&mut self, message: &[u8]) {
-        let new_message = Message {
-            id: self.sequence_local,
-            size: message.len() as u16,
-            data: message.to_vec(),
-        };
-        self.send_queue[self.sequence_local as usize % BUFFER_SIZE] = Some(new_message);
-        self.sequence_local = self.sequence_local.wrapping_add(1);
+    // Returns the message's id. Reliable messages get a `Delivery` once
+    // acked or given up on.
+    pub fn queue_message(
+        &mut self,
+        message: &[u8],
+        deadline: Option<Instant>,
+    ) -> Result<Sequence, SendError> {
+        // Only reliable channels can put fragments back together, an
+        // unreliable message that doesn't fit in a packet is dropped.
+        let count = message.len().div_ceil(FRAGMENT_SIZE).max(1);
+        if count > 1 && (!self.kind.is_reliable() || message.len() > MAX_MESSAGE_SIZE) {
+            return Err(SendError::TooLarge);
+        }
+        // Never reuse a slot that still holds a message, or the id of one
+        // that is still waiting on some of its fragments
+        let id = self.sequence_local;
+        if self.send_queue_len() + count > BUFFER_SIZE || self.pending.contains_key(&id) {
+            return Err(SendError::QueueFull);
+        }
+
+        if count == 1 {
+            self.push_message(None, message, deadline);
+        } else {
+            // Fragments take consecutive ids so each one is acked and
+            // resent on its own like any other message
+            for (index, chunk) in message.chunks(FRAGMENT_SIZE).enumerate() {
+                self.push_message(Some((index as u16, count as u16)), chunk, deadline);
+            }
+        }
+        self.push_pending(id, count as u16);
+        Ok(id)
+    }
+
+    fn push_message(
+        &mut self,
+        fragment: Option<(u16, u16)>,
+        data: &[u8],
+        deadline: Option<Instant>,
+    ) {
+        let mut new_message = Message::new(self.sequence_local, fragment, data.to_vec());
+        new_message.deadline = deadline;
+        if self.kind.is_reliable() {
+            self.send_queue.insert(self.sequence_local, new_message);
+        } else {
+            self.unreliable_queue.push_back(new_message);
+        }
+        self.sequence_local = self.sequence_local.next();
+    }
+
+    fn push_pending(&mut self, id: Sequence, fragments: u16) {
+        if self.kind.is_reliable() {
+            let pending = Pending {
+                fragments_left: fragments,
+                first_sent: None,
+            };
+            self.pending.insert(id, pending);
+        }
     }
 
-    pub fn send_next(&mut self, sequence: u16, amt: u16) -> Vec<u8> {
+    // Reliable messages are resent once `resend_after` has passed
+    // without an ack. Resends that fit in their share of `amt` go ahead
+    // of messages that have never been sent, the rest fill what's left.
+    pub fn send_next(
+        &mut self,
+        sequence: Sequence,
+        amt: u16,
+        now: Instant,
+        resend_after: Duration,
+    ) -> Vec<u8> {
+        if !self.kind.is_reliable() {
+            return self.send_next_unreliable(amt);
+        }
+
+        for id in self.recent_acked.range_to(self.sequence_local) {
+            if let Some(message) = self.send_queue.get_mut(id) {
+                if message.deadline.is_some_and(|deadline| now >= deadline) {
+                    message.give_up();
+                    // Reported once, by whichever fragment gets here first
+                    let first = message.first();
+                    if self.pending.remove(&first).is_some() {
+                        self.deliveries.push((first, Delivery::GivenUp));
+                    }
+                }
+            }
+        }
+
+        // Oldest due resends that fit in their share. The oldest always
+        // goes first if it fits at all, or a message bigger than the
+        // share would never get through while new ones keep coming.
+        let mut resends = Vec::new();
+        let mut late = Vec::new();
+        let mut reserved = 0;
+        for id in self.recent_acked.range_to(self.sequence_local) {
+            if let Some(message) = self.send_queue.get(id) {
+                let due = match message.last_sent {
+                    Some(sent) => now - sent >= resend_after,
+                    None => false,
+                };
+                let len = message.encoded_len();
+                if !due {
+                    continue;
+                }
+                if reserved + len <= amt / RESEND_SHARE || (resends.is_empty() && len <= amt) {
+                    reserved += len;
+                    resends.push(id);
+                } else {
+                    late.push(id);
+                }
+            }
+        }
+
         let mut data = Vec::new();
         let mut ack_ids = Vec::new();
         let mut written = 0;
-        let start = self.recent_acked as usize;
-        let end = self.sequence_local as usize;
-
-        for index in start..end {
-            let normlz = index % BUFFER_SIZE;
-            if let Some(message) = &mut self.send_queue[normlz] {
-                written += message.size;
-                if written < amt {
-                    data.append(&mut message_into_vec(&message));
+        for id in self.recent_acked.range_to(self.sequence_local) {
+            if let Some(message) = self.send_queue.get_mut(id) {
+                let len = message.encoded_len();
+                if message.last_sent.is_none() && written + len <= amt - reserved {
+                    written += len;
+                    message.last_sent = Some(now);
+                    if let Some(pending) = self.pending.get_mut(&message.first()) {
+                        pending.first_sent.get_or_insert(now);
+                    }
+                    data.append(&mut message_into_vec(message));
                     ack_ids.push(message.id);
                 }
             }
         }
-        self.awaiting_ack.insert(sequence, ack_ids);
+        // Then the rest of the due resends, in whatever room is left
+        for id in late {
+            if let Some(message) = self.send_queue.get(id) {
+                let len = message.encoded_len();
+                if reserved + written + len <= amt {
+                    reserved += len;
+                    resends.push(id);
+                }
+            }
+        }
+        for id in resends {
+            if let Some(message) = self.send_queue.get_mut(id) {
+                message.last_sent = Some(now);
+                data.append(&mut message_into_vec(message));
+                ack_ids.push(message.id);
+            }
+        }
+
+        if !ack_ids.is_empty() {
+            self.awaiting_ack.insert(sequence, ack_ids);
+        }
         data
     }
 
-    pub fn acknowledge(&mut self, pid: u16) {
-        if let Some(ids) = self.awaiting_ack.get(&pid) {
-            for id in ids.iter() {
-                let index = *id as usize % BUFFER_SIZE;
-                if let Some(msg) = &mut self.send_queue[index] {
-                    if self.recent_acked < msg.id {
-                        self.recent_acked = msg.id;
-                    }
-                    self.send_queue[index] = None;
+    // Unreliable messages are written once and forgotten, anything that
+    // doesn't fit waits for the next packet
+    fn send_next_unreliable(&mut self, amt: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut written = 0;
+
+        while let Some(message) = self.unreliable_queue.pop_front() {
+            let len = message.encoded_len();
+            if written + len > amt {
+                self.unreliable_queue.push_front(message);
+                break;
+            }
+            written += len;
+            data.append(&mut message_into_vec(&message));
+        }
+        data
+    }
+
+    pub fn acknowledge(&mut self, pid: Sequence, now: Instant) {
+        if let Some(ids) = self.awaiting_ack.remove(pid) {
+            for id in ids {
+                if let Some(message) = self.send_queue.remove(id) {
+                    self.on_acked(message.first(), now);
                 }
             }
-            self.awaiting_ack.remove(&pid);
+        }
+
+        // Only move past messages once everything before them is acked,
+        // an ack for a later message doesn't mean earlier ones arrived
+        while self.recent_acked != self.sequence_local
+            && !self.send_queue.contains(self.recent_acked)
+        {
+            self.recent_acked = self.recent_acked.next();
         }
     }
 
+    fn on_acked(&mut self, first: Sequence, now: Instant) {
+        let pending = match self.pending.get_mut(&first) {
+            Some(pending) => pending,
+            None => return,
+        };
+        pending.fragments_left -= 1;
+        if pending.fragments_left == 0 {
+            let first_sent = pending.first_sent.unwrap_or(now);
+            self.pending.remove(&first);
+            self.deliveries
+                .push((first, Delivery::Acked(now - first_sent)));
+        }
+    }
+
+    // Reliable messages acked or given up on since the last call
+    pub fn deliveries(&mut self) -> Vec<(Sequence, Delivery)> {
+        let mut deliveries = Vec::new();
+        deliveries.append(&mut self.deliveries);
+        deliveries
+    }
+
     // Receiving -- receive message internally -> recv all queued messages
+    // Slots in use, by messages not yet sent or sent and not yet acked.
+    // Reliable messages free theirs in order, so one waiting on an ack
+    // holds up the slots of those after it. Fragments take a slot each.
+    pub fn send_queue_len(&self) -> usize {
+        if self.kind.is_reliable() {
+            self.sequence_local.distance(self.recent_acked) as usize
+        } else {
+            self.unreliable_queue.len()
+        }
+    }
+
+    pub fn send_queue_capacity(&self) -> usize {
+        BUFFER_SIZE
+    }
+
+    // Messages waiting on earlier ones or to be picked up
+    pub fn recv_queue_len(&self) -> usize {
+        self.recv_queue.len() + self.recv.len()
+    }
+
     pub fn recv_next_all(&mut self) -> Vec<Vec<u8>> {
         let mut r = Vec::new();
         r.append(&mut self.recv);
@@ -109,53 +412,521 @@ impl MessageQueue {
         let mut index = 0;
         while index < len && len - index >= MESSAGE_HEADER_LENGTH {
             // extract headers
-            let id = ((slice[index] as u16) << 8) | slice[index + 1] as u16;
+            let id = Sequence::from_be_bytes([slice[index], slice[index + 1]]);
             let size = ((slice[index + 2] as u16) << 8) | slice[index + 3] as u16;
             index += MESSAGE_HEADER_LENGTH;
 
-            // Extract data based on headers.
-            // Currently will panic if headers are incorrect.
+            let mut fragment = None;
+            if size & FRAGMENT_FLAG != 0 {
+                if len - index < FRAGMENT_HEADER_LENGTH {
+                    return;
+                }
+                let frag_index = ((slice[index] as u16) << 8) | slice[index + 1] as u16;
+                let frag_count = ((slice[index + 2] as u16) << 8) | slice[index + 3] as u16;
+                fragment = Some((frag_index, frag_count));
+                index += FRAGMENT_HEADER_LENGTH;
+            }
+            let given_up = size & GIVEN_UP_FLAG != 0;
+            let size = size & !(FRAGMENT_FLAG | GIVEN_UP_FLAG);
+
+            // Extract data based on headers, a size running past the
+            // end of the block means the rest can't be trusted
             let new_index = index + size as usize;
-            let data = slice[index..new_index].to_vec();
+            if new_index > len {
+                return;
+            }
+            // Only reliable channels send fragments
+            if fragment.is_some() && !self.kind.is_reliable() {
+                index = new_index;
+                continue;
+            }
+            let mut message = Message::new(id, fragment, slice[index..new_index].to_vec());
+            message.given_up = given_up;
 
-            if id == self.sequence_remote {
-                self.recv.push(data);
-                self.sequence_remote = self.sequence_remote.wrapping_add(1);
-            } else {
-                self.recv_queue.push(Message { id, size, data });
+            match self.kind {
+                ChannelKind::ReliableOrdered => {
+                    if id == self.sequence_remote {
+                        self.deliver(message);
+                        self.sequence_remote = self.sequence_remote.next();
+                    } else if id > self.sequence_remote
+                        && (id.distance(self.sequence_remote) as usize) < BUFFER_SIZE
+                        && !self.recv_ids.contains(id)
+                    {
+                        // The sender can't be further ahead than its own
+                        // buffer, and resent copies are only queued once
+                        self.recv_ids.insert(id, ());
+                        self.recv_queue.push(message);
+                    }
+                }
+                ChannelKind::ReliableUnordered => {
+                    // Resends can arrive more than once, only deliver the first
+                    if !self.recv_ids.contains(id) && self.recv_ids.insert(id, ()) {
+                        self.deliver(message);
+                    }
+                }
+                ChannelKind::Unreliable => self.deliver(message),
+                ChannelKind::UnreliableSequenced => {
+                    // Drop anything older than what has already been delivered
+                    if id >= self.sequence_remote {
+                        self.deliver(message);
+                        self.sequence_remote = id.next();
+                    }
+                }
             }
             index = new_index;
         }
 
-        // move queued ordered messages from queue to recv if prev have been received
-        let mut expected = true;
-        while expected {
-            expected = if let Some(msg) = self.recv_queue.peek() {
-                msg.id == self.sequence_remote
-            } else {
-                false
-            };
+        if self.kind != ChannelKind::ReliableOrdered {
+            return;
+        }
 
-            if expected {
+        // move queued ordered messages from queue to recv if prev have been received
+        loop {
+            // Drop resent duplicates of already delivered messages, they
+            // would otherwise sit in front of the next expected one
+            while let Some(msg) = self.recv_queue.peek() {
+                if msg.id >= self.sequence_remote {
+                    break;
+                }
                 let msg = self.recv_queue.pop().unwrap();
-                self.recv.push(msg.data);
+                self.recv_ids.remove(msg.id);
+            }
 
-                self.sequence_remote = self.sequence_remote.wrapping_add(1);
+            match self.recv_queue.peek() {
+                Some(msg) if msg.id == self.sequence_remote => {
+                    let msg = self.recv_queue.pop().unwrap();
+                    self.recv_ids.remove(msg.id);
+                    self.deliver(msg);
+                    self.sequence_remote = self.sequence_remote.next();
+                }
+                _ => break,
             }
         }
     }
+
+    // Hands a message to the application, holding fragments back
+    // until every part of their message has arrived
+    fn deliver(&mut self, message: Message) {
+        // Nothing to hand over, and the rest of its fragments aren't coming
+        if message.given_up {
+            self.fragments.remove(&message.first());
+            return;
+        }
+
+        let (index, count) = match message.fragment {
+            Some(fragment) => fragment,
+            None => {
+                self.recv.push(message.data);
+                return;
+            }
+        };
+        if count as usize > MAX_FRAGMENTS || index >= count {
+            return;
+        }
+
+        let first = message.id.wrapping_sub(index);
+        if !self.fragments.contains_key(&first) {
+            // Whatever the sender still has in flight is within a buffer
+            // of this message, older partial messages aren't finishing
+            let window = BUFFER_SIZE as u16;
+            self.fragments.retain(|other, _| {
+                first.distance(*other) < window || other.distance(first) < window
+            });
+            if self.fragments.len() >= MAX_FRAGMENTS {
+                return;
+            }
+        }
+        let reassembly = self.fragments.entry(first).or_insert_with(|| Reassembly {
+            received: 0,
+            parts: vec![None; count as usize],
+        });
+        if reassembly.parts.len() != count as usize {
+            return;
+        }
+
+        let part = &mut reassembly.parts[index as usize];
+        if part.is_none() {
+            *part = Some(message.data);
+            reassembly.received += 1;
+        }
+
+        if reassembly.received == count {
+            let reassembly = self.fragments.remove(&first).unwrap();
+            let data = reassembly.parts.into_iter().flatten().flatten().collect();
+            self.recv.push(data);
+        }
+    }
 }
 
 fn message_into_vec(message: &Message) -> Vec<u8> {
     let mut vec = Vec::new();
 
-    vec.push((message.id >> 8) as u8);
-    vec.push(message.id as u8);
+    vec.extend_from_slice(&message.id.to_be_bytes());
 
-    vec.push((message.size >> 8) as u8);
-    vec.push(message.size as u8);
+    let mut size = match message.fragment {
+        Some(_) => message.size | FRAGMENT_FLAG,
+        None => message.size,
+    };
+    if message.given_up {
+        size |= GIVEN_UP_FLAG;
+    }
+    vec.push((size >> 8) as u8);
+    vec.push(size as u8);
+
+    if let Some((index, count)) = message.fragment {
+        vec.push((index >> 8) as u8);
+        vec.push(index as u8);
+        vec.push((count >> 8) as u8);
+        vec.push(count as u8);
+    }
 
     vec.append(&mut message.data.clone());
 
     vec
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deliver(kind: ChannelKind, order: &[u16]) -> Vec<Vec<u8>> {
+        let mut recv = MessageQueue::new(kind);
+        for id in order {
+            let message = Message::new(Sequence(*id), None, vec![*id as u8]);
+            recv.recv_messages(&message_into_vec(&message));
+        }
+        recv.recv_next_all()
+    }
+
+    #[test]
+    fn test_channel_delivery() {
+        let order = [1, 0, 0, 3, 2, 4];
+        assert_eq!(
+            deliver(ChannelKind::ReliableOrdered, &order),
+            vec![vec![0], vec![1], vec![2], vec![3], vec![4]]
+        );
+        assert_eq!(
+            deliver(ChannelKind::ReliableUnordered, &order),
+            vec![vec![1], vec![0], vec![3], vec![2], vec![4]]
+        );
+        assert_eq!(
+            deliver(ChannelKind::Unreliable, &order),
+            vec![vec![1], vec![0], vec![0], vec![3], vec![2], vec![4]]
+        );
+        assert_eq!(
+            deliver(ChannelKind::UnreliableSequenced, &order),
+            vec![vec![1], vec![3], vec![4]]
+        );
+        assert_eq!(
+            deliver(ChannelKind::UnreliableSequenced, &[0, 0, 1]),
+            vec![vec![0], vec![1]]
+        );
+    }
+
+    #[test]
+    fn test_ordered_resend_in_one_packet() {
+        let packet = |ids: &[u16]| {
+            let mut data = Vec::new();
+            for id in ids {
+                let message = Message::new(Sequence(*id), None, vec![*id as u8]);
+                data.append(&mut message_into_vec(&message));
+            }
+            data
+        };
+
+        // 1 is delivered straight from the second packet while its first
+        // copy is still queued, which mustn't hold up 2
+        let mut recv = MessageQueue::new(ChannelKind::ReliableOrdered);
+        recv.recv_messages(&packet(&[1, 2]));
+        recv.recv_messages(&packet(&[0, 1]));
+        assert_eq!(recv.recv_next_all(), vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn test_truncated_message() {
+        let message = Message::new(Sequence(0), None, vec![0, 1, 2, 3]);
+        let vec = message_into_vec(&message);
+        let mut recv = MessageQueue::new(ChannelKind::Unreliable);
+        recv.recv_messages(&vec[..vec.len() - 1]);
+        assert!(recv.recv_next_all().is_empty());
+    }
+
+    #[test]
+    fn test_resend_after_timeout() {
+        let mut send = MessageQueue::new(ChannelKind::ReliableOrdered);
+        let resend_after = Duration::from_millis(100);
+        let now = Instant::now();
+        send.queue_message(b"first", None).unwrap();
+        send.queue_message(b"second", None).unwrap();
+        assert_eq!(
+            send.send_next(Sequence(0), 1200, now, resend_after).len(),
+            2 * 4 + 11
+        );
+
+        // Nothing to resend until the timeout, new messages go first
+        let later = now + Duration::from_millis(50);
+        assert!(send
+            .send_next(Sequence(1), 1200, later, resend_after)
+            .is_empty());
+        send.queue_message(b"third", None).unwrap();
+        let data = send.send_next(Sequence(2), 1200, later, resend_after);
+        assert_eq!(&data[4..], b"third");
+
+        // Only the unacked messages come round again, new ones first
+        send.acknowledge(Sequence(0), later);
+        send.queue_message(b"fourth", None).unwrap();
+        let due = now + Duration::from_millis(150);
+        let mut recv = MessageQueue::new(ChannelKind::ReliableUnordered);
+        recv.recv_messages(&send.send_next(Sequence(3), 1200, due, resend_after));
+        assert_eq!(
+            recv.recv_next_all(),
+            vec![b"fourth".to_vec(), b"third".to_vec()]
+        );
+
+        // Past their share, resends wait behind new messages for room
+        send.queue_message(b"fifth", None).unwrap();
+        let mut recv = MessageQueue::new(ChannelKind::ReliableUnordered);
+        recv.recv_messages(&send.send_next(Sequence(4), 20, due + resend_after, resend_after));
+        assert_eq!(
+            recv.recv_next_all(),
+            vec![b"fifth".to_vec(), b"third".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_large_resends() {
+        let resend_after = Duration::from_millis(100);
+        let now = Instant::now();
+        let mut send = MessageQueue::new(ChannelKind::ReliableOrdered);
+        let big = vec![1; 400];
+        let huge = vec![2; FRAGMENT_SIZE + 500];
+        send.queue_message(&big, None).unwrap();
+        send.queue_message(&huge, None).unwrap();
+
+        // Both go out in two packets that are lost, then come round
+        // again even though each part is bigger than the resend share
+        for seq in 0..2 {
+            assert!(!send
+                .send_next(Sequence(seq), 1200, now, resend_after)
+                .is_empty());
+        }
+        assert!(send
+            .send_next(Sequence(2), 1200, now, resend_after)
+            .is_empty());
+        let mut recv = MessageQueue::new(ChannelKind::ReliableOrdered);
+        let mut later = now;
+        for seq in 3..5 {
+            later += resend_after;
+            let data = send.send_next(Sequence(seq), 1200, later, resend_after);
+            recv.recv_messages(&data);
+            send.acknowledge(Sequence(seq), later);
+        }
+        assert_eq!(recv.recv_next_all(), vec![big, huge]);
+        assert_eq!(send.send_queue_len(), 0);
+    }
+
+    #[test]
+    fn test_ids_wrap() {
+        let start = Sequence(u16::MAX - 2);
+        let mut send = MessageQueue::new(ChannelKind::ReliableOrdered);
+        let mut recv = MessageQueue::new(ChannelKind::ReliableOrdered);
+        send.sequence_local = start;
+        send.recent_acked = start;
+        recv.sequence_remote = start;
+        for i in 0..6 {
+            send.queue_message(&[i], None).unwrap();
+        }
+
+        // The first send is lost, and the resend has to find messages
+        // on both sides of the wrap
+        let resend_after = Duration::from_millis(100);
+        let now = Instant::now();
+        let lost = send.send_next(Sequence(10), 1200, now, resend_after);
+        assert_eq!(lost.len(), 6 * 5);
+        let resent = send.send_next(Sequence(11), 1200, now + resend_after, resend_after);
+        assert_eq!(resent, lost);
+
+        // Ids 0 to 2 wait behind 65533 to 65535
+        recv.recv_messages(&resent[15..]);
+        assert!(recv.recv_next_all().is_empty());
+        recv.recv_messages(&resent);
+        assert_eq!(
+            recv.recv_next_all(),
+            (0..6).map(|i| vec![i]).collect::<Vec<_>>()
+        );
+
+        send.acknowledge(Sequence(11), now + resend_after);
+        assert_eq!(send.send_queue_len(), 0);
+        assert_eq!(send.recent_acked, Sequence(3));
+    }
+
+    #[test]
+    fn test_fragment_reassembly() {
+        let big: Vec<u8> = (0..FRAGMENT_SIZE * 3 + 10).map(|i| i as u8).collect();
+
+        for kind in [ChannelKind::ReliableOrdered, ChannelKind::ReliableUnordered].iter() {
+            let mut send = MessageQueue::new(*kind);
+            let mut recv = MessageQueue::new(*kind);
+            send.queue_message(b"before", None).unwrap();
+            send.queue_message(&big, None).unwrap();
+            send.queue_message(b"after", None).unwrap();
+            let now = Instant::now();
+
+            // One fragment per packet, delivered in reverse
+            let mut packets = Vec::new();
+            for seq in 0..6 {
+                let seq = Sequence(seq);
+                packets.push(send.send_next(seq, 1200, now, Duration::from_millis(100)));
+                send.acknowledge(seq, now);
+            }
+            for packet in packets.iter().rev() {
+                recv.recv_messages(packet);
+            }
+
+            let messages = recv.recv_next_all();
+            assert_eq!(messages.len(), 3);
+            assert!(messages.contains(&big));
+            if *kind == ChannelKind::ReliableOrdered {
+                assert_eq!(
+                    messages,
+                    vec![b"before".to_vec(), big.clone(), b"after".to_vec()]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_fragment_flood() {
+        let kinds = [
+            ChannelKind::ReliableOrdered,
+            ChannelKind::ReliableUnordered,
+            ChannelKind::Unreliable,
+            ChannelKind::UnreliableSequenced,
+        ];
+        for kind in kinds.iter() {
+            // Second fragments of 60k different messages that never finish
+            let mut recv = MessageQueue::new(*kind);
+            for id in 1..60_000 {
+                let message = Message::new(Sequence(id), Some((1, 512)), vec![0; 8]);
+                recv.recv_messages(&message_into_vec(&message));
+            }
+            assert!(recv.fragments.len() <= MAX_FRAGMENTS);
+            assert!(recv.recv_queue.len() < BUFFER_SIZE);
+            assert!(recv.recv_next_all().is_empty());
+            if !kind.is_reliable() {
+                assert!(recv.fragments.is_empty());
+            }
+        }
+
+        // Copies of one early message are only held once
+        let mut recv = MessageQueue::new(ChannelKind::ReliableOrdered);
+        let message = message_into_vec(&Message::new(Sequence(1), None, vec![1]));
+        for _ in 0..10_000 {
+            recv.recv_messages(&message);
+        }
+        assert_eq!(recv.recv_queue.len(), 1);
+    }
+
+    #[test]
+    fn test_delivery_reports() {
+        let resend_after = Duration::from_millis(100);
+        let now = Instant::now();
+        let later = now + Duration::from_millis(40);
+        let big = vec![7; FRAGMENT_SIZE + 1];
+        let mut send = MessageQueue::new(ChannelKind::ReliableOrdered);
+        assert_eq!(send.queue_message(&big, None), Ok(Sequence(0)));
+        assert_eq!(send.queue_message(b"soon", Some(now)), Ok(Sequence(2)));
+        assert_eq!(send.queue_message(b"last", None), Ok(Sequence(3)));
+
+        // Past its deadline before it ever went out, the receiver only
+        // gets an empty stand in for it
+        let mut recv = MessageQueue::new(ChannelKind::ReliableOrdered);
+        recv.recv_messages(&send.send_next(Sequence(0), 1200, now, resend_after));
+        recv.recv_messages(&send.send_next(Sequence(1), 1200, now, resend_after));
+        assert_eq!(send.deliveries(), vec![(Sequence(2), Delivery::GivenUp)]);
+        assert_eq!(recv.recv_next_all(), vec![big, b"last".to_vec()]);
+
+        // The big message is only acked once both fragments are, the
+        // first packet also carried the other two
+        send.acknowledge(Sequence(1), later);
+        assert!(send.deliveries().is_empty());
+        send.acknowledge(Sequence(0), later);
+        assert_eq!(
+            send.deliveries(),
+            vec![
+                (Sequence(0), Delivery::Acked(later - now)),
+                (Sequence(3), Delivery::Acked(later - now))
+            ]
+        );
+        assert_eq!(send.send_queue_len(), 0);
+    }
+
+    #[test]
+    fn test_delivery_of_largest_message() {
+        let resend_after = Duration::from_millis(100);
+        let now = Instant::now();
+        let later = now + resend_after;
+        let mut send = MessageQueue::new(ChannelKind::ReliableOrdered);
+        let id = send
+            .queue_message(&vec![0; MAX_MESSAGE_SIZE], None)
+            .unwrap();
+
+        // Every fragment but the last is acked, the window stays behind it
+        for i in 0..MAX_FRAGMENTS as u16 {
+            send.send_next(Sequence(i), 1200, now, resend_after);
+            if i + 1 < MAX_FRAGMENTS as u16 {
+                send.acknowledge(Sequence(i), now);
+            }
+        }
+        // Which lets the newest id reach a whole buffer past the first
+        for i in 0..=MAX_FRAGMENTS {
+            send.queue_message(&[i as u8], None).unwrap();
+        }
+
+        let packet = Sequence(MAX_FRAGMENTS as u16);
+        send.send_next(packet, 1200, later, resend_after);
+        send.acknowledge(packet, later);
+        assert!(send
+            .deliveries()
+            .contains(&(id, Delivery::Acked(later - now))));
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let resend_after = Duration::from_millis(100);
+        let now = Instant::now();
+        let mut send = MessageQueue::new(ChannelKind::ReliableOrdered);
+        for i in 0..BUFFER_SIZE {
+            send.queue_message(&[i as u8], None).unwrap();
+        }
+        assert_eq!(send.queue_message(b"more", None), Err(SendError::QueueFull));
+        assert_eq!(send.send_queue_len(), send.send_queue_capacity());
+
+        // Acks for later messages don't help while the oldest is missing
+        let first = send.send_next(Sequence(0), 5, now, resend_after);
+        assert_eq!(first.len(), 5);
+        send.send_next(Sequence(1), 1200, now, resend_after);
+        send.acknowledge(Sequence(1), now);
+        assert_eq!(send.queue_message(b"more", None), Err(SendError::QueueFull));
+        send.acknowledge(Sequence(0), now);
+        assert_eq!(send.send_queue_len(), BUFFER_SIZE - 1 - 1200 / 5);
+        assert!(send.queue_message(b"more", None).is_ok());
+
+        // A message bigger than the free slots is turned away whole
+        let big = vec![0; FRAGMENT_SIZE * (BUFFER_SIZE / 4)];
+        assert_eq!(send.queue_message(&big, None), Err(SendError::QueueFull));
+
+        let mut unreliable = MessageQueue::new(ChannelKind::Unreliable);
+        assert_eq!(
+            unreliable.queue_message(&[0; FRAGMENT_SIZE + 1], None),
+            Err(SendError::TooLarge)
+        );
+        for _ in 0..BUFFER_SIZE {
+            unreliable.queue_message(&[0], None).unwrap();
+        }
+        assert_eq!(
+            unreliable.queue_message(&[0], None),
+            Err(SendError::QueueFull)
+        );
+    }
+}
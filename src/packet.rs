@@ -1,71 +1,381 @@
+use crc32fast::Hasher;
+
+use crate::crypto::{self, Key, ReplayProtection, MAC_SIZE};
+use crate::sequence::Sequence;
+
+// Identifies our packets, anything else on the port is dropped
+pub const PROTOCOL_ID: u32 = 0x4e45_5443;
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// Protocol id + version + packet type
+pub const PACKET_HEADER_LENGTH: usize = 6;
+// CRC32 of the rest of the packet
+pub const CHECKSUM_LENGTH: usize = 4;
+// Sealed packets carry the sequence used as their nonce after the header
+const SEALED_SEQUENCE_LENGTH: usize = 8;
+// Set on the packet type when the body is encrypted
+const SEALED_FLAG: u8 = 0x80;
+
+// Connection requests are padded to this many bytes so the server
+// never answers an unverified address with more than it was sent
+pub const CONNECTION_REQUEST_SIZE: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PacketType {
+    Payload,
+    Keepalive,
+    ConnectionRequest,
+    Challenge,
+    Denied,
+    Disconnect,
+    // Answered instead of a challenge when every slot is taken
+    ServerFull,
+    Ack,
+}
+
+impl PacketType {
+    fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(PacketType::Payload),
+            1 => Some(PacketType::Keepalive),
+            2 => Some(PacketType::ConnectionRequest),
+            3 => Some(PacketType::Challenge),
+            4 => Some(PacketType::Denied),
+            5 => Some(PacketType::Disconnect),
+            6 => Some(PacketType::ServerFull),
+            7 => Some(PacketType::Ack),
+            _ => None,
+        }
+    }
+
+    fn into_u8(self) -> u8 {
+        match self {
+            PacketType::Payload => 0,
+            PacketType::Keepalive => 1,
+            PacketType::ConnectionRequest => 2,
+            PacketType::Challenge => 3,
+            PacketType::Denied => 4,
+            PacketType::Disconnect => 5,
+            PacketType::ServerFull => 6,
+            PacketType::Ack => 7,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Packet {
+    Payload(Payload),
+    Keepalive,
+    ConnectionRequest(ConnectionRequest),
+    Challenge(Challenge),
+    Denied,
+    Disconnect,
+    ServerFull,
+    Ack(Acks),
+}
+
 #[derive(Debug, PartialEq)]
-pub struct Packet {
-    pub sequence: u16,
-    pub ack: u16,
-    pub acks: Vec<u16>,
+pub struct Payload {
+    pub sequence: Sequence,
+    pub ack: Sequence,
+    pub acks: Vec<Sequence>,
     pub data: Vec<u8>,
 }
 
-#[derive(Debug)]
+// Acks sent on their own when there is no payload to carry them. They
+// have no sequence of their own so are never acked back.
+#[derive(Debug, PartialEq)]
+pub struct Acks {
+    pub ack: Sequence,
+    pub acks: Vec<Sequence>,
+}
+
+// The token is empty when the server doesn't require one, the cookie
+// is empty until the server has sent a challenge
+#[derive(Debug, PartialEq)]
+pub struct ConnectionRequest {
+    pub token: Vec<u8>,
+    pub cookie: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Challenge {
+    pub cookie: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
 pub enum ParseError {
     SliceTooShort,
+    WrongProtocol,
+    UnsupportedVersion(u8),
+    UnknownPacketType,
+    RequestNotPadded,
+    ChecksumMismatch,
+    // Sealed packet but no keys to open it with
+    Sealed,
+    // Plaintext packet on a connection that requires encryption
+    NotSealed,
+    Replayed,
+    DecryptFailed,
 }
 
 impl Packet {
-    pub fn new(sequence: u16, ack: u16, acks: Vec<u16>, data: Vec<u8>) -> Self {
-        Packet {
-            sequence,
-            ack,
-            acks,
-            data,
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Packet::Payload(_) => PacketType::Payload,
+            Packet::Keepalive => PacketType::Keepalive,
+            Packet::ConnectionRequest(_) => PacketType::ConnectionRequest,
+            Packet::Challenge(_) => PacketType::Challenge,
+            Packet::Denied => PacketType::Denied,
+            Packet::Disconnect => PacketType::Disconnect,
+            Packet::ServerFull => PacketType::ServerFull,
+            Packet::Ack(_) => PacketType::Ack,
+        }
+    }
+
+    // Handshake packets are always sent in the clear, everything else
+    // is sealed once a connection has keys
+    pub fn is_handshake(&self) -> bool {
+        match self {
+            Packet::ConnectionRequest(_)
+            | Packet::Challenge(_)
+            | Packet::Denied
+            | Packet::ServerFull => true,
+            Packet::Payload(_) | Packet::Keepalive | Packet::Disconnect | Packet::Ack(_) => false,
         }
     }
 
     pub fn from_slice(slice: &[u8]) -> Result<Self, ParseError> {
-        if slice.len() < 8 {
+        Packet::decode(slice, None)
+    }
+
+    // Replayed packets are rejected before decrypting, the window only
+    // moves forward once the packet has been authenticated
+    pub fn open(
+        slice: &[u8],
+        key: &Key,
+        replay: &mut ReplayProtection,
+    ) -> Result<Self, ParseError> {
+        Packet::decode(slice, Some((key, replay)))
+    }
+
+    fn decode(
+        slice: &[u8],
+        keys: Option<(&Key, &mut ReplayProtection)>,
+    ) -> Result<Self, ParseError> {
+        if slice.len() < PACKET_HEADER_LENGTH + CHECKSUM_LENGTH {
             return Err(ParseError::SliceTooShort);
         }
 
-        let sequence = ((slice[0] as u16) << 8) | slice[1] as u16;
-        let ack = ((slice[2] as u16) << 8) | slice[3] as u16;
+        let protocol_id = ((slice[0] as u32) << 24)
+            | ((slice[1] as u32) << 16)
+            | ((slice[2] as u32) << 8)
+            | slice[3] as u32;
+        if protocol_id != PROTOCOL_ID {
+            return Err(ParseError::WrongProtocol);
+        }
+        if slice[4] != PROTOCOL_VERSION {
+            return Err(ParseError::UnsupportedVersion(slice[4]));
+        }
+
+        let (slice, checksum) = slice.split_at(slice.len() - CHECKSUM_LENGTH);
+        let checksum = ((checksum[0] as u32) << 24)
+            | ((checksum[1] as u32) << 16)
+            | ((checksum[2] as u32) << 8)
+            | checksum[3] as u32;
+        if checksum != crc32(slice) {
+            return Err(ParseError::ChecksumMismatch);
+        }
 
-        let bits = ((slice[4] as u32) << 24)
-            | ((slice[5] as u32) << 16)
-            | ((slice[6] as u32) << 8)
-            | slice[7] as u32;
+        let sealed = slice[5] & SEALED_FLAG != 0;
+        let packet_type =
+            PacketType::from_u8(slice[5] & !SEALED_FLAG).ok_or(ParseError::UnknownPacketType)?;
 
-        let mut acks: Vec<u16> = Vec::new();
-        for i in 0..32 {
-            if bits & (1 << i) != 0 {
-                acks.push(ack.wrapping_sub(i))
-            };
+        let body = match (sealed, keys) {
+            (false, None) => slice[PACKET_HEADER_LENGTH..].to_vec(),
+            (false, Some(_)) => match packet_type {
+                PacketType::ConnectionRequest
+                | PacketType::Challenge
+                | PacketType::Denied
+                | PacketType::ServerFull => slice[PACKET_HEADER_LENGTH..].to_vec(),
+                _ => return Err(ParseError::NotSealed),
+            },
+            (true, None) => return Err(ParseError::Sealed),
+            (true, Some((key, replay))) => {
+                let header_length = PACKET_HEADER_LENGTH + SEALED_SEQUENCE_LENGTH;
+                if slice.len() < header_length + MAC_SIZE {
+                    return Err(ParseError::SliceTooShort);
+                }
+                let mut sequence = [0; SEALED_SEQUENCE_LENGTH];
+                sequence.copy_from_slice(&slice[PACKET_HEADER_LENGTH..header_length]);
+                let sequence = u64::from_be_bytes(sequence);
+
+                if replay.already_received(sequence) {
+                    return Err(ParseError::Replayed);
+                }
+                let (header, ciphertext) = slice.split_at(header_length);
+                let body = crypto::open(key, sequence, header, ciphertext)
+                    .ok_or(ParseError::DecryptFailed)?;
+                replay.advance(sequence);
+                body
+            }
+        };
+
+        match packet_type {
+            PacketType::Payload => Ok(Packet::Payload(Payload::from_slice(&body)?)),
+            PacketType::Keepalive => Ok(Packet::Keepalive),
+            PacketType::ConnectionRequest => {
+                if body.len() < CONNECTION_REQUEST_SIZE {
+                    return Err(ParseError::RequestNotPadded);
+                }
+                let (token, rest) = read_bytes(&body)?;
+                let (cookie, _) = read_bytes(rest)?;
+                Ok(Packet::ConnectionRequest(ConnectionRequest {
+                    token,
+                    cookie,
+                }))
+            }
+            PacketType::Challenge => {
+                let (cookie, _) = read_bytes(&body)?;
+                Ok(Packet::Challenge(Challenge { cookie }))
+            }
+            PacketType::Denied => Ok(Packet::Denied),
+            PacketType::Disconnect => Ok(Packet::Disconnect),
+            PacketType::ServerFull => Ok(Packet::ServerFull),
+            PacketType::Ack => Ok(Packet::Ack(Acks::from_slice(&body)?)),
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.encode(None)
+    }
+
+    // The header and sequence are authenticated along with the body
+    pub fn into_vec_sealed(self, sequence: u64, key: &Key) -> Vec<u8> {
+        self.encode(Some((sequence, key)))
+    }
+
+    fn encode(self, sealed: Option<(u64, &Key)>) -> Vec<u8> {
+        // Push header
+        let mut vec = PROTOCOL_ID.to_be_bytes().to_vec();
+        vec.push(PROTOCOL_VERSION);
+        match sealed {
+            Some((sequence, _)) => {
+                vec.push(self.packet_type().into_u8() | SEALED_FLAG);
+                vec.extend_from_slice(&sequence.to_be_bytes());
+            }
+            None => vec.push(self.packet_type().into_u8()),
+        }
+
+        let mut body = Vec::new();
+        match self {
+            Packet::Payload(payload) => payload.write(&mut body),
+            Packet::ConnectionRequest(request) => {
+                write_bytes(&mut body, &request.token);
+                write_bytes(&mut body, &request.cookie);
+                body.resize(CONNECTION_REQUEST_SIZE, 0);
+            }
+            Packet::Challenge(challenge) => write_bytes(&mut body, &challenge.cookie),
+            Packet::Ack(acks) => acks.write(&mut body),
+            Packet::Keepalive | Packet::Denied | Packet::Disconnect | Packet::ServerFull => {}
+        }
+
+        match sealed {
+            Some((sequence, key)) => {
+                let body = crypto::seal(key, sequence, &vec, &body);
+                vec.extend_from_slice(&body);
+            }
+            None => vec.append(&mut body),
+        }
+
+        // Push checksum
+        let checksum = crc32(&vec);
+        vec.push((checksum >> 24) as u8);
+        vec.push((checksum >> 16) as u8);
+        vec.push((checksum >> 8) as u8);
+        vec.push(checksum as u8);
+
+        vec
+    }
+}
+
+impl Payload {
+    pub fn new(sequence: Sequence, ack: Sequence, acks: Vec<Sequence>, data: Vec<u8>) -> Self {
+        Payload {
+            sequence,
+            ack,
+            acks,
+            data,
+        }
+    }
+
+    fn from_slice(slice: &[u8]) -> Result<Self, ParseError> {
+        if slice.len() < 8 {
+            return Err(ParseError::SliceTooShort);
         }
 
+        let sequence = Sequence::from_be_bytes([slice[0], slice[1]]);
+        let Acks { ack, acks } = Acks::from_slice(&slice[2..8])?;
         let data = slice[8..].to_vec();
 
-        Ok(Packet {
+        Ok(Payload {
             sequence,
             ack,
             acks,
             data,
         })
     }
-    // to_vec?
-    pub fn into_vec(mut self) -> Vec<u8> {
-        let mut vec = Vec::new();
 
+    fn write(mut self, vec: &mut Vec<u8>) {
         // Push sent sequence number
-        vec.push((self.sequence >> 8) as u8);
-        vec.push(self.sequence as u8);
+        vec.extend_from_slice(&self.sequence.to_be_bytes());
+
+        let acks = Acks::new(self.ack, self.acks);
+        acks.write(vec);
+
+        vec.append(&mut self.data);
+    }
+}
+
+impl Acks {
+    pub fn new(ack: Sequence, acks: Vec<Sequence>) -> Self {
+        Acks { ack, acks }
+    }
+
+    fn from_slice(slice: &[u8]) -> Result<Self, ParseError> {
+        if slice.len() < 6 {
+            return Err(ParseError::SliceTooShort);
+        }
+
+        let ack = Sequence::from_be_bytes([slice[0], slice[1]]);
+
+        let bits = ((slice[2] as u32) << 24)
+            | ((slice[3] as u32) << 16)
+            | ((slice[4] as u32) << 8)
+            | slice[5] as u32;
+
+        let mut acks: Vec<Sequence> = Vec::new();
+        for i in 0..32 {
+            if bits & (1 << i) != 0 {
+                acks.push(ack.wrapping_sub(i))
+            };
+        }
+
+        Ok(Acks { ack, acks })
+    }
 
+    fn write(&self, vec: &mut Vec<u8>) {
         // Push received sequence number
-        vec.push((self.ack >> 8) as u8);
-        vec.push((self.ack) as u8);
+        vec.extend_from_slice(&self.ack.to_be_bytes());
 
-        // Set bits for each sequence to ack
+        // Set bits for each sequence to ack, bit 0 is `ack` itself and
+        // anything further back than the bitset covers is left out
         let mut ack_bits: u32 = 0;
         for seq in self.acks.iter() {
-            ack_bits |= 1 << self.get_bit_index(*seq);
+            let index = self.ack.distance(*seq);
+            if index < 32 {
+                ack_bits |= 1 << index;
+            }
         }
 
         // Push bitset
@@ -73,31 +383,184 @@ impl Packet {
         vec.push((ack_bits >> 16) as u8);
         vec.push((ack_bits >> 8) as u8);
         vec.push(ack_bits as u8);
+    }
+}
 
-        vec.append(&mut self.data);
+// Seeded with the protocol id so packets from other protocols that
+// happen to share our header still fail the check
+fn crc32(slice: &[u8]) -> u32 {
+    let mut hasher = Hasher::new_with_initial(PROTOCOL_ID);
+    hasher.update(slice);
+    hasher.finalize()
+}
 
-        vec
-    }
+// Length prefixed, for tokens and cookies
+fn write_bytes(vec: &mut Vec<u8>, bytes: &[u8]) {
+    vec.push(bytes.len() as u8);
+    vec.extend_from_slice(bytes);
+}
 
-    fn get_bit_index(&self, seq: u16) -> u32 {
-        if seq > self.ack {
-            (self.ack + (std::u16::MAX - seq)) as u32
-        } else {
-            (self.ack - seq) as u32
-        }
+fn read_bytes(slice: &[u8]) -> Result<(Vec<u8>, &[u8]), ParseError> {
+    if slice.is_empty() || slice.len() < 1 + slice[0] as usize {
+        return Err(ParseError::SliceTooShort);
     }
+    let end = 1 + slice[0] as usize;
+    Ok((slice[1..end].to_vec(), &slice[end..]))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto::Keys;
+
+    fn sequences(values: &[u16]) -> Vec<Sequence> {
+        values.iter().map(|value| Sequence(*value)).collect()
+    }
+
+    fn payload(sequence: u16, ack: u16, acks: &[u16], data: &[u8]) -> Payload {
+        Payload::new(
+            Sequence(sequence),
+            Sequence(ack),
+            sequences(acks),
+            data.to_vec(),
+        )
+    }
 
     #[test]
     fn test_serialize_deserialize() {
-        let packet = Packet::new(5, 7, vec![7, 5, 3, 2, 1], vec![]);
+        let packet = Packet::Payload(payload(5, 7, &[7, 5, 3, 2, 1], &[]));
         let vec = packet.into_vec();
         let new = Packet::from_slice(&vec).unwrap();
-        assert_eq!(Packet::new(5, 7, vec![7, 5, 3, 2, 1], vec![]), new);
+        assert_eq!(Packet::Payload(payload(5, 7, &[7, 5, 3, 2, 1], &[])), new);
+
+        let acks = Packet::Ack(Acks::new(Sequence(9), sequences(&[9, 8, 1])));
+        let vec = acks.into_vec();
+        assert_eq!(vec.len(), PACKET_HEADER_LENGTH + 6 + CHECKSUM_LENGTH);
+        assert_eq!(
+            Packet::from_slice(&vec),
+            Ok(Packet::Ack(Acks::new(Sequence(9), sequences(&[9, 8, 1]))))
+        );
+
+        // Acks reaching back past 0 keep their place in the bitset
+        let wrapped = Packet::Payload(payload(3, 1, &[1, 0, u16::MAX, u16::MAX - 29], &[]));
+        assert_eq!(
+            Packet::from_slice(&wrapped.into_vec()),
+            Ok(Packet::Payload(payload(
+                3,
+                1,
+                &[1, 0, u16::MAX, u16::MAX - 29],
+                &[]
+            )))
+        );
+    }
+
+    #[test]
+    fn test_control_packets() {
+        let packet = Packet::Challenge(Challenge {
+            cookie: vec![1, 2, 3],
+        });
+        let new = Packet::from_slice(&packet.into_vec()).unwrap();
+        assert_eq!(
+            new,
+            Packet::Challenge(Challenge {
+                cookie: vec![1, 2, 3]
+            })
+        );
+
+        let request = Packet::ConnectionRequest(ConnectionRequest {
+            token: vec![4; 10],
+            cookie: vec![5; 2],
+        });
+        let vec = request.into_vec();
+        assert_eq!(
+            Packet::from_slice(&vec),
+            Ok(Packet::ConnectionRequest(ConnectionRequest {
+                token: vec![4; 10],
+                cookie: vec![5; 2],
+            }))
+        );
+        assert_eq!(
+            vec.len(),
+            PACKET_HEADER_LENGTH + CONNECTION_REQUEST_SIZE + CHECKSUM_LENGTH
+        );
+    }
+
+    #[test]
+    fn test_sealed_packets() {
+        let keys = Keys::generate();
+        let mut replay = ReplayProtection::new();
+        let packet = Packet::Payload(payload(5, 7, &[7], b"secret"));
+        let vec = packet.into_vec_sealed(3, &keys.send);
+        assert!(!vec.windows(6).any(|window| window == b"secret"));
+
+        assert_eq!(Packet::from_slice(&vec), Err(ParseError::Sealed));
+        assert_eq!(
+            Packet::open(&vec, &keys.recv, &mut replay),
+            Err(ParseError::DecryptFailed)
+        );
+        assert_eq!(
+            Packet::open(&vec, &keys.send, &mut replay),
+            Ok(Packet::Payload(payload(5, 7, &[7], b"secret")))
+        );
+        assert_eq!(
+            Packet::open(&vec, &keys.send, &mut replay),
+            Err(ParseError::Replayed)
+        );
+        // A forged sequence at the top of the range is checked before
+        // the packet is authenticated
+        let forged = Packet::Keepalive.into_vec_sealed(u64::MAX, &keys.recv);
+        assert_eq!(
+            Packet::open(&forged, &keys.send, &mut replay),
+            Err(ParseError::DecryptFailed)
+        );
+
+        let plain = Packet::Disconnect.into_vec();
+        assert_eq!(
+            Packet::open(&plain, &keys.send, &mut replay),
+            Err(ParseError::NotSealed)
+        );
+        assert_eq!(
+            Packet::open(&Packet::Denied.into_vec(), &keys.send, &mut replay),
+            Ok(Packet::Denied)
+        );
+        assert_eq!(
+            Packet::open(&Packet::ServerFull.into_vec(), &keys.send, &mut replay),
+            Ok(Packet::ServerFull)
+        );
+    }
+
+    #[test]
+    fn test_rejects_corrupt_packets() {
+        let packet = Packet::Payload(payload(5, 7, &[7, 5], &[0, 1, 2, 3]));
+        let vec = packet.into_vec();
+        for i in PACKET_HEADER_LENGTH..vec.len() {
+            let mut corrupt = vec.clone();
+            corrupt[i] ^= 0x10;
+            assert_eq!(
+                Packet::from_slice(&corrupt),
+                Err(ParseError::ChecksumMismatch)
+            );
+        }
     }
 
+    #[test]
+    fn test_rejects_foreign_packets() {
+        let mut vec = Packet::Disconnect.into_vec();
+        vec.truncate(PACKET_HEADER_LENGTH);
+        vec[5] = 42;
+        let checksum = crc32(&vec);
+        vec.extend_from_slice(&checksum.to_be_bytes());
+        assert_eq!(Packet::from_slice(&vec), Err(ParseError::UnknownPacketType));
+
+        let mut vec = Packet::Disconnect.into_vec();
+        vec[4] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            Packet::from_slice(&vec),
+            Err(ParseError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+
+        let mut vec = Packet::Disconnect.into_vec();
+        vec[0] ^= 0xff;
+        assert_eq!(Packet::from_slice(&vec), Err(ParseError::WrongProtocol));
+    }
 }
@@ -0,0 +1,184 @@
+use std::time::{Duration, Instant};
+
+// Used until the first sample arrives, as in RFC 6298
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(50);
+const MAX_RTO: Duration = Duration::from_secs(5);
+// Smallest variance term added to the RTO, one tick of a 60hz send rate
+const RTO_GRANULARITY: Duration = Duration::from_millis(16);
+
+// Upper bounds on smoothed RTT plus variance for each quality level
+const GOOD_LATENCY: Duration = Duration::from_millis(100);
+const FAIR_LATENCY: Duration = Duration::from_millis(250);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Quality {
+    // No samples yet
+    Unknown,
+    Good,
+    Fair,
+    Poor,
+}
+
+// Round trip time estimates following Jacobson/Karels, samples are the
+// time between sending a packet and getting its ack
+#[derive(Clone, Debug)]
+pub struct RttEstimator {
+    latest: Option<Duration>,
+    smoothed: Duration,
+    variance: Duration,
+    min: Duration,
+    // Inter-arrival jitter, how far the gaps between received packets
+    // stray from the average gap
+    jitter: Duration,
+    interval: Duration,
+    last_arrival: Option<Instant>,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        RttEstimator {
+            latest: None,
+            smoothed: Duration::from_secs(0),
+            variance: Duration::from_secs(0),
+            min: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+            interval: Duration::from_secs(0),
+            last_arrival: None,
+        }
+    }
+
+    pub fn on_sample(&mut self, rtt: Duration) {
+        match self.latest {
+            None => {
+                self.smoothed = rtt;
+                self.variance = rtt / 2;
+                self.min = rtt;
+            }
+            Some(_) => {
+                let error = self.smoothed.abs_diff(rtt);
+                self.variance = (self.variance * 3 + error) / 4;
+                self.smoothed = (self.smoothed * 7 + rtt) / 8;
+                self.min = self.min.min(rtt);
+            }
+        }
+        self.latest = Some(rtt);
+    }
+
+    // Called for every packet received
+    pub fn on_arrival(&mut self, now: Instant) {
+        if let Some(last) = self.last_arrival {
+            let gap = now - last;
+            if self.interval == Duration::from_secs(0) {
+                self.interval = gap;
+            }
+            let deviation = gap.abs_diff(self.interval);
+            self.interval = (self.interval * 15 + gap) / 16;
+            self.jitter = (self.jitter * 15 + deviation) / 16;
+        }
+        self.last_arrival = Some(now);
+    }
+
+    pub fn latest(&self) -> Option<Duration> {
+        self.latest
+    }
+
+    pub fn smoothed(&self) -> Duration {
+        self.smoothed
+    }
+
+    pub fn variance(&self) -> Duration {
+        self.variance
+    }
+
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    // How long to wait for an ack before sending again
+    pub fn rto(&self) -> Duration {
+        if self.latest.is_none() {
+            return INITIAL_RTO;
+        }
+        let rto = self.smoothed + (self.variance * 4).max(RTO_GRANULARITY);
+        rto.max(MIN_RTO).min(MAX_RTO)
+    }
+
+    pub fn quality(&self) -> Quality {
+        if self.latest.is_none() {
+            return Quality::Unknown;
+        }
+        let latency = self.smoothed + self.variance.max(self.jitter);
+        if latency <= GOOD_LATENCY {
+            Quality::Good
+        } else if latency <= FAIR_LATENCY {
+            Quality::Fair
+        } else {
+            Quality::Poor
+        }
+    }
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        RttEstimator::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_rtt_samples() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+        assert_eq!(rtt.quality(), Quality::Unknown);
+
+        rtt.on_sample(ms(80));
+        assert_eq!(rtt.smoothed(), ms(80));
+        assert_eq!(rtt.variance(), ms(40));
+        assert_eq!(rtt.rto(), ms(240));
+
+        rtt.on_sample(ms(40));
+        assert_eq!(rtt.latest(), Some(ms(40)));
+        assert_eq!(rtt.min(), ms(40));
+        assert_eq!(rtt.smoothed(), ms(75));
+        assert_eq!(rtt.variance(), ms(40));
+
+        // Sub-millisecond samples are kept
+        let mut fast = RttEstimator::new();
+        fast.on_sample(Duration::from_micros(300));
+        assert_eq!(fast.smoothed(), Duration::from_micros(300));
+        assert_eq!(fast.rto(), MIN_RTO);
+        assert_eq!(fast.quality(), Quality::Good);
+
+        for _ in 0..100 {
+            rtt.on_sample(ms(400));
+        }
+        assert!(rtt.smoothed() > ms(390));
+        assert_eq!(rtt.quality(), Quality::Poor);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let start = Instant::now();
+        let mut steady = RttEstimator::new();
+        let mut uneven = RttEstimator::new();
+        for i in 0..100 {
+            steady.on_arrival(start + ms(16 * i));
+            let wobble = if i % 2 == 0 { 0 } else { 10 };
+            uneven.on_arrival(start + ms(16 * i + wobble));
+        }
+        assert_eq!(steady.jitter(), ms(0));
+        assert!(uneven.jitter() > ms(5));
+    }
+}
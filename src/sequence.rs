@@ -0,0 +1,182 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+// Half the range, anything further ahead than this is treated as behind
+const HALF: u16 = 0x8000;
+
+// A packet or message sequence number that wraps at u16::MAX. Ordering
+// is decided by which way round the gap is shorter, so 0 comes after
+// 65535, and only holds for sequences within half the range of each
+// other.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Sequence(pub u16);
+
+impl Sequence {
+    pub fn next(self) -> Sequence {
+        Sequence(self.0.wrapping_add(1))
+    }
+
+    pub fn wrapping_add(self, n: u16) -> Sequence {
+        Sequence(self.0.wrapping_add(n))
+    }
+
+    pub fn wrapping_sub(self, n: u16) -> Sequence {
+        Sequence(self.0.wrapping_sub(n))
+    }
+
+    // How many steps forward it takes to get from `earlier` to this
+    pub fn distance(self, earlier: Sequence) -> u16 {
+        self.0.wrapping_sub(earlier.0)
+    }
+
+    // Every sequence from this one up to but not including `end`, going
+    // round through 0 if need be
+    pub fn range_to(self, end: Sequence) -> SequenceRange {
+        SequenceRange {
+            next: self,
+            remaining: end.distance(self),
+        }
+    }
+
+    // For indexing buffers of `size` slots
+    pub fn index(self, size: usize) -> usize {
+        self.0 as usize % size
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 2] {
+        self.0.to_be_bytes()
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 2]) -> Sequence {
+        Sequence(u16::from_be_bytes(bytes))
+    }
+}
+
+impl Ord for Sequence {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.distance(*other) {
+            0 => Ordering::Equal,
+            // Exactly half way round either could be first, fall back to
+            // the raw values so the order is at least consistent
+            HALF => self.0.cmp(&other.0),
+            ahead if ahead < HALF => Ordering::Greater,
+            _ => Ordering::Less,
+        }
+    }
+}
+
+impl PartialOrd for Sequence {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl From<u16> for Sequence {
+    fn from(value: u16) -> Self {
+        Sequence(value)
+    }
+}
+
+impl fmt::Debug for Sequence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+pub struct SequenceRange {
+    next: Sequence,
+    remaining: u16,
+}
+
+impl Iterator for SequenceRange {
+    type Item = Sequence;
+
+    fn next(&mut self) -> Option<Sequence> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let sequence = self.next;
+        self.next = sequence.next();
+        self.remaining -= 1;
+        Some(sequence)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl ExactSizeIterator for SequenceRange {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seq(value: u16) -> Sequence {
+        Sequence(value)
+    }
+
+    #[test]
+    fn test_ordering_across_wrap() {
+        assert!(seq(0) > seq(u16::MAX));
+        assert!(seq(5) > seq(u16::MAX - 5));
+        assert!(seq(u16::MAX) < seq(0));
+        assert!(seq(1) > seq(0));
+        assert_eq!(seq(7).cmp(&seq(7)), Ordering::Equal);
+        assert_eq!(seq(u16::MAX).next(), seq(0));
+        assert_eq!(seq(2).wrapping_sub(4), seq(u16::MAX - 1));
+
+        // Every sequence is behind the next HALF - 1 and ahead of the rest
+        for base in [0, 1, HALF - 1, HALF, HALF + 1, u16::MAX - 1, u16::MAX].iter() {
+            let base = seq(*base);
+            for step in 1..HALF {
+                let ahead = base.wrapping_add(step);
+                assert!(ahead > base, "{:?} > {:?}", ahead, base);
+                assert!(base < ahead, "{:?} < {:?}", base, ahead);
+                assert_eq!(ahead.distance(base), step);
+                assert_eq!(base.distance(ahead), 0u16.wrapping_sub(step));
+            }
+            // Half way round is still ordered one way only
+            let opposite = base.wrapping_add(HALF);
+            assert_ne!(base.cmp(&opposite), opposite.cmp(&base));
+        }
+    }
+
+    #[test]
+    fn test_range_across_wrap() {
+        let range: Vec<_> = seq(u16::MAX - 2).range_to(seq(2)).collect();
+        assert_eq!(
+            range,
+            vec![
+                seq(u16::MAX - 2),
+                seq(u16::MAX - 1),
+                seq(u16::MAX),
+                seq(0),
+                seq(1)
+            ]
+        );
+        assert_eq!(seq(10).range_to(seq(10)).count(), 0);
+        assert_eq!(seq(10).range_to(seq(14)).len(), 4);
+
+        // The same length from any starting point
+        for start in (0..=u16::MAX).step_by(7) {
+            let start = seq(start);
+            let end = start.wrapping_add(40);
+            let range: Vec<_> = start.range_to(end).collect();
+            assert_eq!(range.len(), 40);
+            assert_eq!(range[0], start);
+            assert_eq!(range[39].next(), end);
+            assert!(range.windows(2).all(|pair| pair[1] > pair[0]));
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        for value in [0, 1, 0xff, 0x100, HALF, u16::MAX].iter() {
+            let sequence = seq(*value);
+            assert_eq!(Sequence::from_be_bytes(sequence.to_be_bytes()), sequence);
+        }
+        assert_eq!(seq(0x1234).to_be_bytes(), [0x12, 0x34]);
+        assert_eq!(seq(130).index(128), 2);
+    }
+}
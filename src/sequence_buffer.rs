@@ -0,0 +1,164 @@
+use crate::sequence::Sequence;
+
+// Fixed number of slots indexed by sequence. Each slot remembers which
+// sequence it holds, so a lookup for a sequence that has since been
+// replaced by one a whole buffer later finds nothing.
+pub struct SequenceBuffer<T> {
+    entries: Vec<Option<(Sequence, T)>>,
+    // One past the newest sequence inserted, None while empty
+    next: Option<Sequence>,
+}
+
+impl<T> SequenceBuffer<T> {
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0 && size <= u16::MAX as usize / 2);
+        SequenceBuffer {
+            entries: (0..size).map(|_| None).collect(),
+            next: None,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Moving past the newest sequence clears the slots in between, they
+    // hold entries a whole buffer old. Returns false, leaving the buffer
+    // as it was, for sequences too old to fit.
+    pub fn insert(&mut self, sequence: Sequence, value: T) -> bool {
+        match self.next {
+            Some(next) if sequence < next => {
+                if next.distance(sequence) as usize > self.capacity() {
+                    return false;
+                }
+            }
+            Some(next) => {
+                if sequence.distance(next) as usize >= self.capacity() {
+                    self.clear();
+                } else {
+                    for stale in next.range_to(sequence) {
+                        let index = stale.index(self.capacity());
+                        self.entries[index] = None;
+                    }
+                }
+                self.next = Some(sequence.next());
+            }
+            None => self.next = Some(sequence.next()),
+        }
+
+        let index = sequence.index(self.capacity());
+        self.entries[index] = Some((sequence, value));
+        true
+    }
+
+    pub fn get(&self, sequence: Sequence) -> Option<&T> {
+        match &self.entries[sequence.index(self.capacity())] {
+            Some((stored, value)) if *stored == sequence => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, sequence: Sequence) -> Option<&mut T> {
+        let index = sequence.index(self.capacity());
+        match &mut self.entries[index] {
+            Some((stored, value)) if *stored == sequence => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, sequence: Sequence) -> bool {
+        self.get(sequence).is_some()
+    }
+
+    pub fn remove(&mut self, sequence: Sequence) -> Option<T> {
+        if !self.contains(sequence) {
+            return None;
+        }
+        let index = sequence.index(self.capacity());
+        self.entries[index].take().map(|(_, value)| value)
+    }
+
+    pub fn clear(&mut self) {
+        for entry in self.entries.iter_mut() {
+            *entry = None;
+        }
+        self.next = None;
+    }
+
+    // Occupied slots in slot order, not sequence order
+    pub fn iter(&self) -> impl Iterator<Item = (Sequence, &T)> + '_ {
+        self.entries
+            .iter()
+            .flatten()
+            .map(|(sequence, value)| (*sequence, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut buffer = SequenceBuffer::new(8);
+        assert!(buffer.insert(Sequence(3), "three"));
+        assert_eq!(buffer.get(Sequence(3)), Some(&"three"));
+        assert_eq!(buffer.get(Sequence(11)), None);
+        *buffer.get_mut(Sequence(3)).unwrap() = "drei";
+        assert_eq!(buffer.remove(Sequence(3)), Some("drei"));
+        assert_eq!(buffer.remove(Sequence(3)), None);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_stale_entries_are_evicted() {
+        let mut buffer = SequenceBuffer::new(8);
+        for i in 0..6 {
+            buffer.insert(Sequence(i), i);
+        }
+        // Skipping ahead clears what was in the slots passed over
+        assert!(buffer.insert(Sequence(10), 10));
+        assert_eq!(buffer.len(), 4);
+        assert!(!buffer.contains(Sequence(1)));
+        assert!(!buffer.contains(Sequence(2)));
+        assert_eq!(buffer.get(Sequence(3)), Some(&3));
+
+        // Late arrivals are let in while they are inside the window
+        assert!(buffer.insert(Sequence(8), 8));
+        assert!(!buffer.insert(Sequence(2), 2));
+        assert_eq!(buffer.get(Sequence(3)), Some(&3));
+
+        // A jump past the whole buffer leaves nothing behind
+        assert!(buffer.insert(Sequence(100), 100));
+        assert_eq!(
+            buffer.iter().collect::<Vec<_>>(),
+            vec![(Sequence(100), &100)]
+        );
+    }
+
+    #[test]
+    fn test_wrap() {
+        let mut buffer = SequenceBuffer::new(16);
+        for i in 0..40u16 {
+            let sequence = Sequence(u16::MAX - 20).wrapping_add(i);
+            assert!(buffer.insert(sequence, i));
+            // Everything a buffer or more behind is gone
+            assert!(!buffer.contains(sequence.wrapping_sub(16)));
+        }
+        let newest = Sequence(u16::MAX - 20).wrapping_add(39);
+        assert_eq!(newest, Sequence(18));
+        for sequence in newest.wrapping_sub(15).range_to(newest.next()) {
+            assert!(buffer.contains(sequence));
+        }
+        assert!(!buffer.insert(Sequence(u16::MAX), 0));
+        assert!(buffer.insert(newest.wrapping_sub(15), 0));
+    }
+}
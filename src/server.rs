@@ -1,92 +1,380 @@
-use rand::prelude::*;
-use std::collections::hash_map::Entry::{Occupied, Vacant};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io;
-use std::iter;
 use std::net::{SocketAddr, UdpSocket};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::connection::Connection;
+use crate::challenge::{unix_secs, ChallengeKey};
+use crate::channel::{ChannelId, MessageId};
+use crate::connection::{Connection, ConnectionState, PacketEvent, StateReason};
+use crate::crypto::Key;
+use crate::message_queue::{Delivery, SendError};
+use crate::packet::{Challenge, Packet, ParseError};
+use crate::sequence::Sequence;
+use crate::stats::ConnectionStats;
+use crate::token::{PrivateToken, TokenError};
+use crate::transport::Transport;
 
-pub struct Server {
-    socket: UdpSocket,
+// Index of the slot a client holds for as long as it is connected,
+// slots are reused once their client has gone
+pub type ClientId = usize;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerEvent {
+    ClientConnected(ClientId),
+    ClientDisconnected(ClientId, StateReason),
+    Message(ClientId, ChannelId, Vec<u8>),
+    // A reliable message reached the client, this long after it first
+    // went out
+    MessageAcked(ClientId, MessageId, Duration),
+    // A reliable message wasn't acked before its deadline
+    MessageGivenUp(ClientId, MessageId),
+    // A payload packet went out to the client with this sequence,
+    // followed later by `PacketAcked` or `PacketLost` for the same one
+    PacketSent(ClientId, Sequence),
+    PacketAcked(ClientId, Sequence),
+    PacketLost(ClientId, Sequence),
+}
+
+pub struct Server<T: Transport = UdpSocket> {
+    socket: T,
     buffer: Vec<u8>,
-    connections: HashMap<SocketAddr, Connection>,
+    // One slot per allowed client, served in slot order
+    clients: Vec<Option<Connection>>,
+    addresses: HashMap<SocketAddr, ClientId>,
+    events: VecDeque<ServerEvent>,
+    challenge_key: ChallengeKey,
+    // Shared with the backend that issues connect tokens
+    token_key: Option<Key>,
+    // Address clients reach us on, which tokens have to name
+    public_addr: SocketAddr,
+    // Tokens that have already admitted a client, until they expire
+    used_tokens: HashMap<Vec<u8>, u64>,
+    // Packets from unknown addresses that failed their checksum
+    corrupt_packets: u32,
+    // Datagrams the transport wouldn't send to their address
+    send_errors: u32,
     local_addr: SocketAddr,
-    max_connections: usize,
 }
 
-impl Server {
+impl Server<UdpSocket> {
     pub fn new(
         addr: SocketAddr,
         max_packet_size: usize,
         max_connections: usize,
     ) -> Result<Self, io::Error> {
-        let socket = UdpSocket::bind(&addr)?;
-        let buffer: Vec<u8> = iter::repeat(0).take(max_packet_size).collect();
-        let connections = HashMap::new();
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Server::with_transport(socket, max_packet_size, max_connections)
+    }
+}
+
+impl<T: Transport> Server<T> {
+    pub fn with_transport(
+        socket: T,
+        max_packet_size: usize,
+        max_connections: usize,
+    ) -> Result<Self, io::Error> {
+        let buffer = vec![0; max_packet_size];
         let local_addr = socket.local_addr()?;
 
         Ok(Server {
             socket,
             buffer,
-            connections,
+            clients: (0..max_connections).map(|_| None).collect(),
+            addresses: HashMap::new(),
+            events: VecDeque::new(),
+            challenge_key: ChallengeKey::generate(),
+            token_key: None,
+            public_addr: local_addr,
+            used_tokens: HashMap::new(),
+            corrupt_packets: 0,
+            send_errors: 0,
             local_addr,
-            max_connections,
         })
     }
 
-    pub fn run(&mut self) {
-        let run_time = 19;
-        let pps = 60;
-        let packet_drop = 0.1;
+    // Only admit clients with a connect token sealed with this key, each
+    // connection is encrypted with the session keys from its token.
+    // Without tokens connections are unencrypted. `public_addr` is the
+    // address the backend puts in tokens, which differs from the bound
+    // address behind NAT or when bound to 0.0.0.0.
+    pub fn require_tokens(&mut self, token_key: Key, public_addr: SocketAddr) {
+        self.token_key = Some(token_key);
+        self.public_addr = public_addr;
+    }
 
-        self.socket.set_nonblocking(true).unwrap();
-        let mut rng = thread_rng();
-        let mut last_sent = Instant::now();
-        let mut count = 0;
-        let start = Instant::now();
+    // Receives everything waiting on the transport, times out quiet
+    // clients and sends to every client due a packet at its send rate.
+    // What happened is picked up with `events`. Only receive errors are
+    // returned, a send to one address failing is counted in
+    // `send_errors` and doesn't hold up anyone else.
+    pub fn update(&mut self, now: Instant) -> io::Result<()> {
         loop {
-            if Instant::now() - start > Duration::from_secs(run_time) {
-                println!("end");
-                break;
+            let (amt, addr) = match self.socket.recv_from(&mut self.buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+            if let Some(&id) = self.addresses.get(&addr) {
+                if let Some(conn) = &mut self.clients[id] {
+                    conn.receive_packet(&self.buffer[..amt], now);
+                    for (channel, msg) in conn.recv_messages() {
+                        self.events
+                            .push_back(ServerEvent::Message(id, channel, msg));
+                    }
+                }
+            } else {
+                self.handle_connection_request(addr, amt, now);
             }
+        }
 
-            if Instant::now() - last_sent > Duration::from_millis(1000 / pps) {
-                for (_, conn) in self.connections.iter_mut() {
-                    conn.queue_message(&format!("ping:{}", count).into_bytes());
-                    conn.send(&mut self.socket).unwrap();
+        for id in 0..self.clients.len() {
+            let conn = match &mut self.clients[id] {
+                Some(conn) => conn,
+                None => continue,
+            };
+            if conn.ready_to_send(now) {
+                if let Some(sequence) = conn.send(now) {
+                    self.events.push_back(ServerEvent::PacketSent(id, sequence));
                 }
-                count += 1;
-                last_sent = Instant::now();
+            } else {
+                conn.update(now);
+            }
+            let failed = flush(&mut self.socket, conn);
+            self.send_errors = self.send_errors.wrapping_add(failed);
+            for (message, delivery) in conn.deliveries() {
+                let event = match delivery {
+                    Delivery::Acked(time) => ServerEvent::MessageAcked(id, message, time),
+                    Delivery::GivenUp => ServerEvent::MessageGivenUp(id, message),
+                };
+                self.events.push_back(event);
             }
+            for event in conn.packet_events() {
+                let event = match event {
+                    PacketEvent::Acked(sequence) => ServerEvent::PacketAcked(id, sequence),
+                    PacketEvent::Lost(sequence) => ServerEvent::PacketLost(id, sequence),
+                };
+                self.events.push_back(event);
+            }
+            if conn.state() == ConnectionState::Disconnected {
+                let reason = conn.state_reason();
+                self.addresses.remove(&conn.remote_addr());
+                self.clients[id] = None;
+                self.events
+                    .push_back(ServerEvent::ClientDisconnected(id, reason));
+            }
+        }
+        Ok(())
+    }
 
-            if let Ok((amt, addr)) = self.socket.recv_from(&mut self.buffer) {
-                if rng.gen::<f32>() < packet_drop {
-                    continue;
+    // Everything that has happened since the last call
+    pub fn events(&mut self) -> impl Iterator<Item = ServerEvent> + '_ {
+        self.events.drain(..)
+    }
+
+    pub fn send_to(
+        &mut self,
+        client: ClientId,
+        channel: ChannelId,
+        message: &[u8],
+    ) -> Result<MessageId, SendError> {
+        match self.clients.get_mut(client) {
+            Some(Some(conn)) => conn.queue_message(channel, message),
+            _ => Err(SendError::NotConnected),
+        }
+    }
+
+    // Reliable messages not acked by `deadline` are given up on, on the
+    // same clock as the times passed to `update`
+    pub fn send_to_with_deadline(
+        &mut self,
+        client: ClientId,
+        channel: ChannelId,
+        message: &[u8],
+        deadline: Instant,
+    ) -> Result<MessageId, SendError> {
+        match self.clients.get_mut(client) {
+            Some(Some(conn)) => conn.queue_message_with_deadline(channel, message, deadline),
+            _ => Err(SendError::NotConnected),
+        }
+    }
+
+    // Returns the clients that couldn't take the message, the rest have
+    // it queued
+    pub fn broadcast(&mut self, channel: ChannelId, message: &[u8]) -> Vec<(ClientId, SendError)> {
+        let mut failed = Vec::new();
+        for (id, slot) in self.clients.iter_mut().enumerate() {
+            if let Some(conn) = slot {
+                if let Err(err) = conn.queue_message(channel, message) {
+                    failed.push((id, err));
                 }
-                match self.connections.entry(addr) {
-                    Occupied(_) => {
-                        for (_addr, conn) in self.connections.iter_mut() {
-                            conn.receive_packet(&self.buffer[..amt]);
-                            let data = conn.recv_messages();
-                            for msg in data.into_iter() {
-                                println!("{}", std::str::from_utf8(&msg).unwrap());
-                            }
-                        }
-                    }
-                    Vacant(_) => {
-                        if self.connections.len() < self.max_connections {
-                            let mut new_con = Connection::new(self.local_addr, addr);
-                            println!("New connection from {}", addr);
-                            new_con.receive_packet(&self.buffer[..amt]);
-                            new_con.queue_message(b"accepted\n");
-                            new_con.send(&mut self.socket).unwrap();
-                            self.connections.insert(addr, new_con);
-                        }
-                    }
-                };
-            };
+            }
+        }
+        failed
+    }
+
+    // Slots taken on `channel`, see `Connection::send_queue_len`
+    pub fn send_queue_len(&self, client: ClientId, channel: ChannelId) -> Option<usize> {
+        match self.clients.get(client) {
+            Some(Some(conn)) => conn.send_queue_len(channel),
+            _ => None,
+        }
+    }
+
+    pub fn send_queue_capacity(&self, client: ClientId, channel: ChannelId) -> Option<usize> {
+        match self.clients.get(client) {
+            Some(Some(conn)) => conn.send_queue_capacity(channel),
+            _ => None,
+        }
+    }
+
+    // Disconnect packets go out on the following calls to `update`, then
+    // the client's slot is freed
+    pub fn disconnect(&mut self, client: ClientId, now: Instant) {
+        if let Some(Some(conn)) = self.clients.get_mut(client) {
+            conn.disconnect(now);
+        }
+    }
+
+    pub fn clients(&self) -> Vec<ClientId> {
+        self.clients
+            .iter()
+            .enumerate()
+            .filter_map(|(id, slot)| slot.as_ref().map(|_| id))
+            .collect()
+    }
+
+    pub fn client_addr(&self, client: ClientId) -> Option<SocketAddr> {
+        match self.clients.get(client) {
+            Some(Some(conn)) => Some(conn.remote_addr()),
+            _ => None,
+        }
+    }
+
+    pub fn client_id(&self, addr: SocketAddr) -> Option<ClientId> {
+        self.addresses.get(&addr).cloned()
+    }
+
+    // The client id from the connect token the client was admitted with
+    pub fn client_user_id(&self, client: ClientId) -> Option<u64> {
+        match self.clients.get(client) {
+            Some(Some(conn)) => conn.user_id(),
+            _ => None,
+        }
+    }
+
+    pub fn stats(&self, client: ClientId, now: Instant) -> Option<ConnectionStats> {
+        match self.clients.get(client) {
+            Some(Some(conn)) => Some(conn.stats(now)),
+            _ => None,
+        }
+    }
+
+    // Packets from unknown addresses that failed their checksum
+    pub fn corrupt_packets(&self) -> u32 {
+        self.corrupt_packets
+    }
+
+    // Datagrams the transport refused, such as ones to port 0
+    pub fn send_errors(&self) -> u32 {
+        self.send_errors
+    }
+
+    // Unknown addresses have to echo back a signed challenge cookie
+    // before they are given a connection. Nothing is stored until then,
+    // so spoofed requests can't use up connection slots.
+    fn handle_connection_request(&mut self, addr: SocketAddr, amt: usize, now: Instant) {
+        let request = match Packet::from_slice(&self.buffer[..amt]) {
+            Ok(Packet::ConnectionRequest(request)) => request,
+            Err(ParseError::ChecksumMismatch) => {
+                self.corrupt_packets = self.corrupt_packets.wrapping_add(1);
+                return;
+            }
+            _ => return,
+        };
+
+        let wall_time = SystemTime::now();
+        let token = match self.token_key {
+            Some(token_key) => match self.check_token(&token_key, &request.token, wall_time) {
+                Ok(token) => Some(token),
+                // The token is genuine, just not usable here
+                Err(TokenError::WrongServer) | Err(TokenError::Reused) => {
+                    self.send_packet(Packet::Denied, addr);
+                    return;
+                }
+                // Nothing has been verified yet so don't answer
+                Err(_) => return,
+            },
+            None => None,
+        };
+
+        if !self.challenge_key.verify(&addr, &request.cookie, wall_time) {
+            let cookie = self.challenge_key.cookie(&addr, wall_time);
+            let challenge = Packet::Challenge(Challenge { cookie });
+            self.send_packet(challenge, addr);
+            return;
+        }
+
+        let id = match self.clients.iter().position(|slot| slot.is_none()) {
+            Some(id) => id,
+            None => {
+                self.send_packet(Packet::ServerFull, addr);
+                return;
+            }
+        };
+
+        let mut new_con = Connection::new(self.local_addr, addr, now);
+        if let Some(token) = token {
+            self.used_tokens.insert(request.token, token.expires);
+            new_con.set_keys(token.keys);
+            new_con.set_user_id(token.client_id);
+        }
+        new_con.accept(now);
+        let sequence = new_con.send(now);
+        let failed = flush(&mut self.socket, &mut new_con);
+        self.send_errors = self.send_errors.wrapping_add(failed);
+        self.clients[id] = Some(new_con);
+        self.addresses.insert(addr, id);
+        self.events.push_back(ServerEvent::ClientConnected(id));
+        if let Some(sequence) = sequence {
+            self.events.push_back(ServerEvent::PacketSent(id, sequence));
+        }
+    }
+
+    // Handshake answers to addresses without a connection
+    fn send_packet(&mut self, packet: Packet, addr: SocketAddr) {
+        if self.socket.send_to(&packet.into_vec(), addr).is_err() {
+            self.send_errors = self.send_errors.wrapping_add(1);
+        }
+    }
+
+    fn check_token(
+        &mut self,
+        token_key: &Key,
+        token: &[u8],
+        now: SystemTime,
+    ) -> Result<PrivateToken, TokenError> {
+        let private = PrivateToken::open(token_key, token, now)?;
+        if !private.server_addresses.contains(&self.public_addr) {
+            return Err(TokenError::WrongServer);
+        }
+
+        let unix_now = unix_secs(now);
+        self.used_tokens.retain(|_, expires| *expires >= unix_now);
+        if self.used_tokens.contains_key(token) {
+            return Err(TokenError::Reused);
+        }
+        Ok(private)
+    }
+}
+
+// Returns how many datagrams the transport refused
+fn flush<T: Transport>(socket: &mut T, conn: &mut Connection) -> u32 {
+    let mut failed = 0;
+    while let Some(datagram) = conn.poll_transmit() {
+        if socket.send_to(&datagram, conn.remote_addr()).is_err() {
+            failed += 1;
         }
     }
+    failed
 }
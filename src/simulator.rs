@@ -0,0 +1,149 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::conditioner::{Link, LinkConfig};
+use crate::transport::Transport;
+
+// An in-process network on a clock that only moves when told to, so
+// whole sessions can be run in a test without real sockets or waiting.
+// Every socket's outgoing traffic goes through its own `Link`, seeded
+// from the network's seed, so runs are reproducible.
+#[derive(Clone)]
+pub struct VirtualNetwork {
+    shared: Rc<RefCell<Shared>>,
+}
+
+struct Shared {
+    now: Instant,
+    seed: u64,
+    bound: u64,
+    default_link: LinkConfig,
+    // Ordered so delivery order doesn't depend on hashing
+    links: BTreeMap<SocketAddr, Link>,
+    inboxes: BTreeMap<SocketAddr, VecDeque<(SocketAddr, Vec<u8>)>>,
+}
+
+impl Shared {
+    // Moves everything that has arrived by now into the inboxes
+    fn deliver(&mut self) {
+        let now = self.now;
+        for (from, link) in self.links.iter_mut() {
+            while let Some((to, data)) = link.poll(now) {
+                if let Some(inbox) = self.inboxes.get_mut(&to) {
+                    inbox.push_back((*from, data));
+                }
+            }
+        }
+    }
+}
+
+impl VirtualNetwork {
+    pub fn new(seed: u64) -> Self {
+        VirtualNetwork {
+            shared: Rc::new(RefCell::new(Shared {
+                now: Instant::now(),
+                seed,
+                bound: 0,
+                default_link: LinkConfig::default(),
+                links: BTreeMap::new(),
+                inboxes: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<VirtualSocket> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.inboxes.contains_key(&addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let seed = shared.seed.wrapping_add(shared.bound);
+        shared.bound += 1;
+        let link = Link::new(shared.default_link, seed);
+        shared.links.insert(addr, link);
+        shared.inboxes.insert(addr, VecDeque::new());
+        Ok(VirtualSocket {
+            addr,
+            shared: self.shared.clone(),
+        })
+    }
+
+    // Used for sockets bound after this is called
+    pub fn set_default_link(&self, config: LinkConfig) {
+        self.shared.borrow_mut().default_link = config;
+    }
+
+    // Conditions for everything `addr` sends from now on
+    pub fn set_link(&self, addr: SocketAddr, config: LinkConfig) {
+        if let Some(link) = self.shared.borrow_mut().links.get_mut(&addr) {
+            link.set_config(config);
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.shared.borrow().now
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut shared = self.shared.borrow_mut();
+        shared.now += duration;
+        shared.deliver();
+    }
+}
+
+pub struct VirtualSocket {
+    addr: SocketAddr,
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl Transport for VirtualSocket {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        // Refused by real sockets too
+        if addr.port() == 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let mut shared = self.shared.borrow_mut();
+        let now = shared.now;
+        if let Some(link) = shared.links.get_mut(&self.addr) {
+            link.send(now, addr, buf);
+        }
+        // Anything sent without delay arrives straight away
+        shared.deliver();
+        Ok(buf.len())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut shared = self.shared.borrow_mut();
+        match shared
+            .inboxes
+            .get_mut(&self.addr)
+            .and_then(|inbox| inbox.pop_front())
+        {
+            Some((from, data)) => {
+                let amt = data.len().min(buf.len());
+                buf[..amt].copy_from_slice(&data[..amt]);
+                Ok((amt, from))
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn now(&self) -> Instant {
+        self.shared.borrow().now
+    }
+}
+
+impl Drop for VirtualSocket {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.links.remove(&self.addr);
+        shared.inboxes.remove(&self.addr);
+    }
+}
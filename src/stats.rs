@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::rtt::Quality;
+
+// Bandwidth is averaged over this much time
+const BANDWIDTH_WINDOW: Duration = Duration::from_secs(1);
+
+// A snapshot of a connection, taken with `stats`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionStats {
+    pub sent_packets: u32,
+    pub recv_packets: u32,
+    pub acked_packets: u32,
+    pub lost_packets: u32,
+    pub corrupt_packets: u32,
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+    // Percentage of recently sent packets that went unacked
+    pub packet_loss: f32,
+    pub rtt: Duration,
+    pub rtt_variance: Duration,
+    pub min_rtt: Duration,
+    pub latest_rtt: Option<Duration>,
+    pub jitter: Duration,
+    pub rto: Duration,
+    pub quality: Quality,
+    // Bytes per second over the last second
+    pub bandwidth_out: f32,
+    pub bandwidth_in: f32,
+    // What congestion control currently allows
+    pub send_rate: u32,
+    pub allowed_bandwidth: f32,
+    // Set while the application should cut back what it sends
+    pub congested: bool,
+    // Messages waiting to be sent or acked
+    pub send_queue: usize,
+    // Messages waiting on earlier ones or to be picked up
+    pub recv_queue: usize,
+    // Datagrams waiting for `poll_transmit`
+    pub outgoing: usize,
+}
+
+// Bytes sent or received over a sliding window
+#[derive(Clone, Debug, Default)]
+pub struct BandwidthMeter {
+    total: u64,
+    recent: VecDeque<(Instant, usize)>,
+}
+
+impl BandwidthMeter {
+    pub fn new() -> Self {
+        BandwidthMeter::default()
+    }
+
+    pub fn record(&mut self, now: Instant, bytes: usize) {
+        self.total += bytes as u64;
+        self.recent.push_back((now, bytes));
+        while let Some(&(at, _)) = self.recent.front() {
+            if now - at <= BANDWIDTH_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // Bytes per second
+    pub fn rate(&self, now: Instant) -> f32 {
+        let bytes: usize = self
+            .recent
+            .iter()
+            .filter(|(at, _)| now - *at <= BANDWIDTH_WINDOW)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        bytes as f32 / BANDWIDTH_WINDOW.as_secs_f32()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bandwidth_window() {
+        let start = Instant::now();
+        let mut meter = BandwidthMeter::new();
+        for i in 0..10 {
+            meter.record(start + Duration::from_millis(i * 100), 100);
+        }
+        assert_eq!(meter.total(), 1000);
+        assert_eq!(meter.rate(start + Duration::from_millis(900)), 1000.0);
+        assert_eq!(meter.rate(start + Duration::from_millis(1450)), 500.0);
+        assert_eq!(meter.rate(start + Duration::from_secs(5)), 0.0);
+    }
+}
@@ -0,0 +1,228 @@
+use rand::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use crate::challenge::unix_secs;
+use crate::crypto::{self, Key, Keys, KEY_SIZE, MAC_SIZE};
+use crate::packet::PROTOCOL_ID;
+
+pub const MAX_SERVER_ADDRESSES: usize = 4;
+// Expiry + nonce in the clear, then the sealed private token. Sized
+// for the most addresses so every token is the same length.
+pub const TOKEN_SIZE: usize = 8 + 8 + PRIVATE_TOKEN_SIZE + MAC_SIZE;
+// Client id + address count + addresses + both session keys
+const PRIVATE_TOKEN_SIZE: usize = 8 + 1 + MAX_SERVER_ADDRESSES * ADDRESS_SIZE + KEY_SIZE * 2;
+// Address type + IPv6 address + port
+const ADDRESS_SIZE: usize = 1 + 16 + 2;
+
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    Malformed,
+    Expired,
+    Invalid,
+    WrongServer,
+    Reused,
+}
+
+// Handed to a client by the backend. The client can read which servers
+// it may connect to and its session keys, the private part is sealed
+// with a key only the backend and the servers know.
+#[derive(Clone)]
+pub struct ConnectToken {
+    pub server_addresses: Vec<SocketAddr>,
+    pub expires: u64,
+    pub keys: Keys,
+    pub private: Vec<u8>,
+}
+
+impl ConnectToken {
+    pub fn generate(
+        private_key: &Key,
+        client_id: u64,
+        server_addresses: Vec<SocketAddr>,
+        lifetime: Duration,
+        now: SystemTime,
+    ) -> ConnectToken {
+        assert!(!server_addresses.is_empty() && server_addresses.len() <= MAX_SERVER_ADDRESSES);
+
+        let keys = Keys::generate();
+        let expires = unix_secs(now + lifetime);
+        let private = PrivateToken {
+            client_id,
+            server_addresses: server_addresses.clone(),
+            expires,
+            keys: keys.reversed(),
+        };
+
+        ConnectToken {
+            server_addresses,
+            expires,
+            keys,
+            private: private.seal(private_key),
+        }
+    }
+}
+
+// What the server learns from a connect token
+pub struct PrivateToken {
+    pub client_id: u64,
+    pub server_addresses: Vec<SocketAddr>,
+    pub expires: u64,
+    // Session keys from the server's side
+    pub keys: Keys,
+}
+
+impl PrivateToken {
+    pub fn open(private_key: &Key, token: &[u8], now: SystemTime) -> Result<Self, TokenError> {
+        if token.len() != TOKEN_SIZE {
+            return Err(TokenError::Malformed);
+        }
+
+        let expires = read_u64(&token[..8]);
+        if expires < unix_secs(now) {
+            return Err(TokenError::Expired);
+        }
+        let nonce = read_u64(&token[8..16]);
+        let plain = crypto::open(private_key, nonce, &associated(expires), &token[16..])
+            .ok_or(TokenError::Invalid)?;
+
+        let client_id = read_u64(&plain[..8]);
+        let count = plain[8] as usize;
+        if count == 0 || count > MAX_SERVER_ADDRESSES {
+            return Err(TokenError::Malformed);
+        }
+        let mut index = 9;
+        let mut server_addresses = Vec::new();
+        for _ in 0..count {
+            server_addresses.push(read_address(&plain[index..index + ADDRESS_SIZE])?);
+            index += ADDRESS_SIZE;
+        }
+
+        let index = 9 + MAX_SERVER_ADDRESSES * ADDRESS_SIZE;
+        let mut send = [0; KEY_SIZE];
+        let mut recv = [0; KEY_SIZE];
+        send.copy_from_slice(&plain[index..index + KEY_SIZE]);
+        recv.copy_from_slice(&plain[index + KEY_SIZE..index + KEY_SIZE * 2]);
+
+        Ok(PrivateToken {
+            client_id,
+            server_addresses,
+            expires,
+            keys: Keys { send, recv },
+        })
+    }
+
+    fn seal(&self, private_key: &Key) -> Vec<u8> {
+        let mut plain = Vec::with_capacity(PRIVATE_TOKEN_SIZE);
+        plain.extend_from_slice(&self.client_id.to_be_bytes());
+        plain.push(self.server_addresses.len() as u8);
+        for addr in self.server_addresses.iter() {
+            write_address(&mut plain, addr);
+        }
+        plain.resize(9 + MAX_SERVER_ADDRESSES * ADDRESS_SIZE, 0);
+        plain.extend_from_slice(&self.keys.send);
+        plain.extend_from_slice(&self.keys.recv);
+
+        // Tokens are sealed with one long lived key, so the nonce is
+        // random rather than a counter
+        let nonce = thread_rng().gen::<u64>();
+        let mut token = self.expires.to_be_bytes().to_vec();
+        token.extend_from_slice(&nonce.to_be_bytes());
+        token.extend_from_slice(&crypto::seal(
+            private_key,
+            nonce,
+            &associated(self.expires),
+            &plain,
+        ));
+        token
+    }
+}
+
+// Ties the token to our protocol and stops the expiry being changed
+fn associated(expires: u64) -> Vec<u8> {
+    let mut vec = PROTOCOL_ID.to_be_bytes().to_vec();
+    vec.extend_from_slice(&expires.to_be_bytes());
+    vec
+}
+
+fn read_u64(slice: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&slice[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn write_address(vec: &mut Vec<u8>, addr: &SocketAddr) {
+    let mut ip = [0; 16];
+    match addr.ip() {
+        IpAddr::V4(v4) => {
+            vec.push(4);
+            ip[..4].copy_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            vec.push(6);
+            ip.copy_from_slice(&v6.octets());
+        }
+    }
+    vec.extend_from_slice(&ip);
+    vec.push((addr.port() >> 8) as u8);
+    vec.push(addr.port() as u8);
+}
+
+fn read_address(slice: &[u8]) -> Result<SocketAddr, TokenError> {
+    let port = ((slice[17] as u16) << 8) | slice[18] as u16;
+    let ip = match slice[0] {
+        4 => IpAddr::V4(Ipv4Addr::new(slice[1], slice[2], slice[3], slice[4])),
+        6 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&slice[1..17]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(TokenError::Malformed),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_open() {
+        let private_key = Keys::generate().send;
+        let server = "127.0.0.1:40000".parse().unwrap();
+        let other = "[::1]:40001".parse().unwrap();
+        let now = SystemTime::now();
+
+        let token = ConnectToken::generate(
+            &private_key,
+            42,
+            vec![server, other],
+            Duration::from_secs(30),
+            now,
+        );
+        assert_eq!(token.private.len(), TOKEN_SIZE);
+
+        let private = PrivateToken::open(&private_key, &token.private, now).unwrap();
+        assert_eq!(private.client_id, 42);
+        assert_eq!(private.server_addresses, vec![server, other]);
+        assert_eq!(private.keys.send, token.keys.recv);
+        assert_eq!(private.keys.recv, token.keys.send);
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            PrivateToken::open(&private_key, &token.private, later).err(),
+            Some(TokenError::Expired)
+        );
+        assert_eq!(
+            PrivateToken::open(&Keys::generate().send, &token.private, now).err(),
+            Some(TokenError::Invalid)
+        );
+
+        let mut tampered = token.private.clone();
+        tampered[7] ^= 1;
+        assert_eq!(
+            PrivateToken::open(&private_key, &tampered, now).err(),
+            Some(TokenError::Invalid)
+        );
+    }
+}
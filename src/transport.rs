@@ -0,0 +1,224 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::Instant;
+
+#[cfg(unix)]
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::net::Ipv4Addr;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+// Where `Client` and `Server` send and receive datagrams. `recv_from`
+// must not block, it returns `WouldBlock` when nothing is waiting.
+pub trait Transport {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    // The clock connections over this transport are timed with, a
+    // simulated network swaps in its own
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+// One end of an in-process link, for running client and server in the
+// same process without a socket
+pub struct MemoryTransport {
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    sender: Sender<(SocketAddr, Vec<u8>)>,
+    receiver: Receiver<(SocketAddr, Vec<u8>)>,
+}
+
+impl MemoryTransport {
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> (MemoryTransport, MemoryTransport) {
+        let (a_sender, b_receiver) = mpsc::channel();
+        let (b_sender, a_receiver) = mpsc::channel();
+        let a_end = MemoryTransport {
+            local_addr: a,
+            remote_addr: b,
+            sender: a_sender,
+            receiver: a_receiver,
+        };
+        let b_end = MemoryTransport {
+            local_addr: b,
+            remote_addr: a,
+            sender: b_sender,
+            receiver: b_receiver,
+        };
+        (a_end, b_end)
+    }
+}
+
+impl Transport for MemoryTransport {
+    // Like UDP, datagrams to anyone but the other end go nowhere
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if addr == self.remote_addr {
+            // The other end hanging up is just more packet loss
+            let _ = self.sender.send((self.local_addr, buf.to_vec()));
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.receiver.try_recv() {
+            Ok((from, data)) => {
+                let amt = data.len().min(buf.len());
+                buf[..amt].copy_from_slice(&data[..amt]);
+                Ok((amt, from))
+            }
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+// Unix datagram sockets are addressed by path, so each path the
+// transport talks to is given a stand in `SocketAddr` on 127.0.0.1
+#[cfg(unix)]
+pub struct UnixTransport {
+    socket: UnixDatagram,
+    local_addr: SocketAddr,
+    paths: HashMap<SocketAddr, PathBuf>,
+    addrs: HashMap<PathBuf, SocketAddr>,
+    next_port: u16,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    pub fn bind<P: AsRef<Path>>(path: P, local_addr: SocketAddr) -> io::Result<UnixTransport> {
+        let socket = UnixDatagram::bind(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(UnixTransport {
+            socket,
+            local_addr,
+            paths: HashMap::new(),
+            addrs: HashMap::new(),
+            next_port: 1,
+        })
+    }
+
+    // Lets us send to a peer before it has sent to us, e.g. a server
+    pub fn add_peer<P: AsRef<Path>>(&mut self, addr: SocketAddr, path: P) {
+        let path = path.as_ref().to_path_buf();
+        self.paths.insert(addr, path.clone());
+        self.addrs.insert(path, addr);
+    }
+
+    fn addr_for(&mut self, path: &Path) -> SocketAddr {
+        if let Some(addr) = self.addrs.get(path) {
+            return *addr;
+        }
+        loop {
+            let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), self.next_port);
+            self.next_port = self.next_port.wrapping_add(1).max(1);
+            if !self.paths.contains_key(&addr) && addr != self.local_addr {
+                self.add_peer(addr, path);
+                return addr;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self.paths.get(&addr) {
+            Some(path) => self.socket.send_to(buf, path),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no unix socket path for {}", addr),
+            )),
+        }
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (amt, from) = self.socket.recv_from(buf)?;
+            // Unbound senders can't be replied to
+            if let Some(path) = from.as_pathname() {
+                let path = path.to_path_buf();
+                return Ok((amt, self.addr_for(&path)));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_pair() {
+        let a = "127.0.0.1:1000".parse().unwrap();
+        let b = "127.0.0.1:2000".parse().unwrap();
+        let (mut a_end, mut b_end) = MemoryTransport::pair(a, b);
+        let mut buf = [0; 16];
+
+        assert_eq!(
+            b_end.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        a_end.send_to(b"hi", b).unwrap();
+        a_end.send_to(b"lost", a).unwrap();
+        assert_eq!(b_end.recv_from(&mut buf).unwrap(), (2, a));
+        assert_eq!(&buf[..2], b"hi");
+        assert!(b_end.recv_from(&mut buf).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_datagram() {
+        let dir = std::env::temp_dir();
+        let server_path = dir.join(format!("netcode-server-{}.sock", std::process::id()));
+        let client_path = dir.join(format!("netcode-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&server_path);
+        let _ = std::fs::remove_file(&client_path);
+
+        let server_addr = "127.0.0.1:2000".parse().unwrap();
+        let client_addr = "127.0.0.1:1000".parse().unwrap();
+        let mut server = UnixTransport::bind(&server_path, server_addr).unwrap();
+        let mut client = UnixTransport::bind(&client_path, client_addr).unwrap();
+        client.add_peer(server_addr, &server_path);
+
+        let mut buf = [0; 16];
+        client.send_to(b"hi", server_addr).unwrap();
+        let (amt, from) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..amt], b"hi");
+
+        server.send_to(b"ho", from).unwrap();
+        assert_eq!(client.recv_from(&mut buf).unwrap(), (2, server_addr));
+        assert_eq!(&buf[..2], b"ho");
+
+        std::fs::remove_file(&server_path).unwrap();
+        std::fs::remove_file(&client_path).unwrap();
+    }
+}
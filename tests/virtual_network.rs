@@ -0,0 +1,818 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use networking::client::{Client, ClientEvent};
+use networking::conditioner::{BurstLoss, Latency, LinkConfig};
+use networking::connection::{ConnectionState, StateReason};
+use networking::crypto::{Key, Keys};
+use networking::sequence::Sequence;
+use networking::server::{ClientId, Server, ServerEvent};
+use networking::simulator::{VirtualNetwork, VirtualSocket};
+use networking::token::ConnectToken;
+
+const STEP: Duration = Duration::from_millis(16);
+
+struct Session {
+    network: VirtualNetwork,
+    server: Server<VirtualSocket>,
+    server_addr: SocketAddr,
+    token_key: Option<Key>,
+    clients: Vec<Client<VirtualSocket>>,
+    events: Vec<ServerEvent>,
+    // Delivery reports are kept apart from the rest
+    receipts: Vec<ServerEvent>,
+    // And so are packet sends and their outcomes
+    packets: Vec<ServerEvent>,
+    // Filled in by `collect_messages`
+    to_server: HashMap<ClientId, Vec<Vec<u8>>>,
+    to_clients: Vec<Vec<Vec<u8>>>,
+}
+
+impl Session {
+    fn new(seed: u64, link: LinkConfig, clients: usize) -> Session {
+        Session::with_tokens(seed, link, clients, Some(Keys::generate().send))
+    }
+
+    // Without tokens every connection is unencrypted, so a packet fed to
+    // the wrong connection isn't rejected and would show up as a mix up
+    fn with_tokens(seed: u64, link: LinkConfig, clients: usize, token_key: Option<Key>) -> Session {
+        let network = VirtualNetwork::new(seed);
+        network.set_default_link(link);
+
+        let server_addr: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let mut server =
+            Server::with_transport(network.bind(server_addr).unwrap(), 1504, clients).unwrap();
+        if let Some(token_key) = token_key {
+            server.require_tokens(token_key, server_addr);
+        }
+
+        let mut session = Session {
+            network,
+            server,
+            server_addr,
+            token_key,
+            clients: Vec::new(),
+            events: Vec::new(),
+            receipts: Vec::new(),
+            packets: Vec::new(),
+            to_server: HashMap::new(),
+            to_clients: Vec::new(),
+        };
+        for _ in 0..clients {
+            session.add_client();
+        }
+        session
+    }
+
+    fn add_client(&mut self) {
+        let i = self.clients.len();
+        let mut client = Client::with_transport(self.network.bind(client_addr(i)).unwrap());
+        match &self.token_key {
+            Some(token_key) => {
+                let token = ConnectToken::generate(
+                    token_key,
+                    i as u64,
+                    vec![self.server_addr],
+                    Duration::from_secs(30),
+                    SystemTime::now(),
+                );
+                client.connect_with_token(token).unwrap();
+            }
+            None => client.connect(self.server_addr).unwrap(),
+        }
+        self.clients.push(client);
+        self.to_clients.push(Vec::new());
+    }
+
+    fn step(&mut self) {
+        self.network.advance(STEP);
+        self.server.update(self.network.now()).unwrap();
+        for event in self.server.events() {
+            match event {
+                ServerEvent::MessageAcked(..) | ServerEvent::MessageGivenUp(..) => {
+                    self.receipts.push(event)
+                }
+                ServerEvent::PacketSent(..)
+                | ServerEvent::PacketAcked(..)
+                | ServerEvent::PacketLost(..) => self.packets.push(event),
+                event => self.events.push(event),
+            }
+        }
+        for client in self.clients.iter_mut() {
+            while client.recv().is_ok() {}
+            client.send_next().unwrap();
+        }
+    }
+
+    // Messages each client has sent the server since the last call
+    fn server_messages(&mut self) -> Vec<(ClientId, Vec<u8>)> {
+        let mut messages = Vec::new();
+        self.events.retain(|event| match event {
+            ServerEvent::Message(client, 0, msg) => {
+                messages.push((*client, msg.clone()));
+                false
+            }
+            _ => true,
+        });
+        messages
+    }
+
+    // Moves what each client sent the server on channel 0, and everything
+    // each client received, into `to_server` and `to_clients`
+    fn collect_messages(&mut self) {
+        for (client, msg) in self.server_messages() {
+            self.to_server.entry(client).or_default().push(msg);
+        }
+        for (client, received) in self.clients.iter_mut().zip(self.to_clients.iter_mut()) {
+            for (_channel, msg) in client.recv_messages().unwrap() {
+                received.push(msg);
+            }
+        }
+    }
+
+    fn connected(&self) -> bool {
+        self.clients
+            .iter()
+            .all(|client| client.state().unwrap().0 == ConnectionState::Connected)
+    }
+}
+
+fn client_addr(i: usize) -> SocketAddr {
+    format!("10.0.1.{}:5000", i + 1).parse().unwrap()
+}
+
+// A client's events apart from those for each packet
+fn client_events(client: &mut Client<VirtualSocket>) -> Vec<ClientEvent> {
+    client
+        .events()
+        .filter(|event| {
+            !matches!(
+                event,
+                ClientEvent::PacketSent(_)
+                    | ClientEvent::PacketAcked(_)
+                    | ClientEvent::PacketLost(_)
+            )
+        })
+        .collect()
+}
+
+fn numbered(messages: &[Vec<u8>]) -> Vec<u32> {
+    messages
+        .iter()
+        .filter(|msg| msg.len() == 4)
+        .map(|msg| u32::from_be_bytes([msg[0], msg[1], msg[2], msg[3]]))
+        .collect()
+}
+
+#[test]
+fn test_many_clients_over_lossy_network() {
+    let link = LinkConfig {
+        latency: Latency::Uniform(Duration::from_millis(30), Duration::from_millis(60)),
+        jitter: Duration::from_millis(10),
+        loss: BurstLoss::uniform(0.1),
+        duplicate: 0.02,
+        reorder: 0.05,
+        ..LinkConfig::default()
+    };
+    let mut session = Session::new(1, link, 16);
+    while !session.connected() {
+        session.step();
+    }
+
+    let count = 300u32;
+    for i in 0..count {
+        for client in session.clients.iter_mut() {
+            client.queue_message(0, i.to_be_bytes().to_vec()).unwrap();
+        }
+        session.server.broadcast(0, &i.to_be_bytes());
+        session.step();
+        session.collect_messages();
+    }
+    // Let resends catch up
+    for _ in 0..200 {
+        session.step();
+        session.collect_messages();
+    }
+
+    let expected: Vec<u32> = (0..count).collect();
+    assert_eq!(session.to_server.len(), 16);
+    for messages in session.to_server.values() {
+        assert_eq!(numbered(messages), expected);
+    }
+    for messages in session.to_clients.iter() {
+        assert_eq!(numbered(messages), expected);
+    }
+    assert!(session
+        .events
+        .iter()
+        .all(|event| matches!(event, ServerEvent::ClientConnected(_))));
+    assert_eq!(session.events.len(), 16);
+}
+
+#[test]
+fn test_large_messages_over_lossy_network() {
+    let link = LinkConfig {
+        latency: Latency::Uniform(Duration::from_millis(30), Duration::from_millis(60)),
+        loss: BurstLoss::uniform(0.1),
+        reorder: 0.05,
+        ..LinkConfig::default()
+    };
+    let mut session = Session::new(7, link, 2);
+    while !session.connected() {
+        session.step();
+    }
+
+    // Bigger than a resend's share of a packet, and split into fragments
+    let sized = |n: u32| {
+        let mut msg = n.to_be_bytes().to_vec();
+        msg.resize([400, 1500, 5000][n as usize % 3], n as u8);
+        msg
+    };
+    let count = 60u32;
+    for n in 0..count + 600 {
+        if n < count {
+            for client in session.clients.iter_mut() {
+                client.queue_message(0, sized(n)).unwrap();
+            }
+            assert!(session.server.broadcast(0, &sized(n)).is_empty());
+        }
+        session.step();
+        session.collect_messages();
+    }
+
+    let expected: Vec<_> = (0..count).map(sized).collect();
+    assert_eq!(session.to_server.len(), 2);
+    for messages in session.to_server.values().chain(session.to_clients.iter()) {
+        assert_eq!(messages, &expected);
+    }
+}
+
+#[test]
+fn test_client_streams_stay_separate() {
+    let link = LinkConfig {
+        latency: Latency::Uniform(Duration::from_millis(20), Duration::from_millis(50)),
+        loss: BurstLoss::uniform(0.05),
+        reorder: 0.05,
+        ..LinkConfig::default()
+    };
+    let mut session = Session::with_tokens(4, link, 40, None);
+    while !session.connected() {
+        session.step();
+    }
+    session.events.clear();
+    let ids: Vec<ClientId> = (0..session.clients.len())
+        .map(|i| session.server.client_id(client_addr(i)).unwrap())
+        .collect();
+
+    // Every message says who sent it or who it is for, so a packet handled
+    // by the wrong connection turns up as a stray or out of order message
+    let tagged = |i: usize, n: u32| {
+        let mut msg = vec![i as u8];
+        msg.extend_from_slice(&n.to_be_bytes());
+        msg
+    };
+    let count = 200u32;
+    for n in 0..count + 200 {
+        if n < count {
+            for (i, client) in session.clients.iter_mut().enumerate() {
+                client.queue_message(0, tagged(i, n)).unwrap();
+                session.server.send_to(ids[i], 0, &tagged(i, n)).unwrap();
+            }
+        }
+        session.step();
+        session.collect_messages();
+    }
+
+    for (i, id) in ids.iter().enumerate() {
+        let expected: Vec<_> = (0..count).map(|n| tagged(i, n)).collect();
+        assert_eq!(session.to_server[id], expected);
+        assert_eq!(session.to_clients[i], expected);
+    }
+    assert!(session.events.is_empty());
+}
+
+#[test]
+fn test_duplicated_packets() {
+    let link = LinkConfig {
+        duplicate: 1.0,
+        ..LinkConfig::default()
+    };
+    // Unencrypted, so the copies aren't caught as replays
+    let mut session = Session::with_tokens(7, link, 1, None);
+    while !session.connected() {
+        session.step();
+    }
+    session.events.clear();
+
+    let count = 50u32;
+    for i in 0..count + 20 {
+        if i < count {
+            session.clients[0]
+                .queue_message(2, i.to_be_bytes().to_vec())
+                .unwrap();
+        }
+        session.step();
+    }
+
+    let received: Vec<Vec<u8>> = session
+        .events
+        .iter()
+        .filter_map(|event| match event {
+            ServerEvent::Message(_, 2, msg) => Some(msg.clone()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(numbered(&received), (0..count).collect::<Vec<_>>());
+    let sent = session.clients[0].stats().unwrap().sent_packets;
+    let id = session.server.client_id(client_addr(0)).unwrap();
+    assert!(
+        session
+            .server
+            .stats(id, session.network.now())
+            .unwrap()
+            .recv_packets
+            <= sent
+    );
+}
+
+#[test]
+fn test_silent_clients_time_out() {
+    let mut session = Session::new(2, LinkConfig::default(), 2);
+    while !session.connected() {
+        session.step();
+    }
+
+    // Everything the first client sends is lost from here on
+    let cut: SocketAddr = "10.0.1.1:5000".parse().unwrap();
+    let dead = LinkConfig {
+        loss: BurstLoss::uniform(1.0),
+        ..LinkConfig::default()
+    };
+    session.network.set_link(cut, dead);
+    let start = session.network.now();
+
+    let cut_id = session.server.client_id(cut).unwrap();
+    while session.server.clients().len() == 2 {
+        session.step();
+    }
+    let waited = session.network.now() - start;
+    assert!(waited > Duration::from_secs(10) && waited < Duration::from_secs(11));
+    assert_eq!(
+        session.events.last(),
+        Some(&ServerEvent::ClientDisconnected(
+            cut_id,
+            StateReason::TimedOut
+        ))
+    );
+    assert_eq!(session.server.client_addr(cut_id), None);
+
+    // With the server gone quiet the client gives up too
+    for _ in 0..(11_000 / 16) {
+        session.step();
+    }
+    assert_eq!(
+        session.clients[0].state(),
+        Some((ConnectionState::Disconnected, StateReason::TimedOut))
+    );
+    assert_eq!(
+        session.clients[1].state(),
+        Some((ConnectionState::Connected, StateReason::Accepted))
+    );
+}
+
+#[test]
+fn test_long_session() {
+    // Unencrypted, as sealing every packet is most of the cost here. The
+    // hour long run below wraps the sequences a few times.
+    long_session(None, Duration::from_secs(600));
+}
+
+#[test]
+#[ignore]
+fn test_hour_long_session() {
+    long_session(Some(Keys::generate().send), Duration::from_secs(3600));
+}
+
+// A message every few packets for `length` of simulated time
+fn long_session(token_key: Option<Key>, length: Duration) {
+    let link = LinkConfig {
+        latency: Latency::Normal {
+            mean: Duration::from_millis(40),
+            std_dev: Duration::from_millis(5),
+        },
+        loss: BurstLoss::uniform(0.05),
+        ..LinkConfig::default()
+    };
+    let mut session = Session::with_tokens(3, link, 1, token_key);
+    while !session.connected() {
+        session.step();
+    }
+
+    let count = (length.as_millis() / (STEP * 7).as_millis()) as u32;
+    let start = session.network.now();
+    let mut received = Vec::new();
+    for i in 0..count + 500 {
+        if i < count {
+            session.clients[0]
+                .queue_message(0, i.to_be_bytes().to_vec())
+                .unwrap();
+        }
+        for _ in 0..7 {
+            session.step();
+        }
+        for (_client, msg) in session.server_messages() {
+            received.push(msg);
+        }
+    }
+
+    assert!(session.network.now() - start > length);
+    assert_eq!(numbered(&received), (0..count).collect::<Vec<_>>());
+    assert_eq!(
+        session.clients[0].state().unwrap().0,
+        ConnectionState::Connected
+    );
+}
+
+#[test]
+fn test_client_slots_are_reused() {
+    let mut session = Session::new(3, LinkConfig::default(), 2);
+    while !session.connected() {
+        session.step();
+    }
+    assert_eq!(
+        session.events.drain(..).collect::<Vec<_>>(),
+        vec![
+            ServerEvent::ClientConnected(0),
+            ServerEvent::ClientConnected(1)
+        ]
+    );
+
+    // No free slot for a third client
+    session.add_client();
+    for _ in 0..20 {
+        session.step();
+    }
+    assert_eq!(
+        session.clients[2].state(),
+        Some((ConnectionState::Disconnected, StateReason::ServerFull))
+    );
+
+    let addr = session.server.client_addr(0).unwrap();
+    session.server.disconnect(0, session.network.now());
+    for _ in 0..20 {
+        session.step();
+    }
+    assert_eq!(
+        session.events.drain(..).collect::<Vec<_>>(),
+        vec![ServerEvent::ClientDisconnected(
+            0,
+            StateReason::LocalDisconnect
+        )]
+    );
+    assert_eq!(
+        session.clients[0].state(),
+        Some((ConnectionState::Disconnected, StateReason::RemoteDisconnect))
+    );
+    assert_eq!(session.server.client_id(addr), None);
+
+    session.clients.remove(2);
+    session.add_client();
+    while session.clients[2].state().unwrap().0 != ConnectionState::Connected {
+        session.step();
+    }
+    assert_eq!(session.events, vec![ServerEvent::ClientConnected(0)]);
+    assert_eq!(session.server.clients(), vec![0, 1]);
+    session.server.send_to(0, 0, b"hello").unwrap();
+    for _ in 0..10 {
+        session.step();
+    }
+    assert_eq!(
+        session.clients[2].recv_messages(),
+        Some(vec![(0, b"hello".to_vec())])
+    );
+}
+
+#[test]
+fn test_failed_sends_dont_stop_the_server() {
+    let mut session = Session::with_tokens(8, LinkConfig::default(), 1, None);
+    while !session.connected() {
+        session.step();
+    }
+
+    // The challenge for a request from port 0 can't be sent
+    let spoofed = "10.0.2.1:0".parse().unwrap();
+    let mut spoofed = Client::with_transport(session.network.bind(spoofed).unwrap());
+    spoofed.connect(session.server_addr).unwrap();
+    session.clients[0]
+        .queue_message(0, b"still here".to_vec())
+        .unwrap();
+    for _ in 0..10 {
+        session.step();
+    }
+    assert!(session.server.send_errors() > 0);
+    assert_eq!(session.server_messages(), vec![(0, b"still here".to_vec())]);
+}
+
+#[test]
+fn test_client_connect_outcomes() {
+    let mut session = Session::new(5, LinkConfig::default(), 1);
+
+    // Nothing panics before connecting
+    let mut idle = Client::with_transport(session.network.bind(client_addr(10)).unwrap());
+    assert_eq!(
+        idle.send_next().unwrap_err().kind(),
+        io::ErrorKind::NotConnected
+    );
+    assert_eq!(idle.recv().unwrap_err().kind(), io::ErrorKind::NotConnected);
+    assert_eq!(idle.recv_messages(), None);
+    assert_eq!(idle.stats(), None);
+    idle.disconnect();
+    assert!(client_events(&mut idle).is_empty());
+
+    while !session.connected() {
+        session.step();
+    }
+    assert_eq!(
+        client_events(&mut session.clients[0]),
+        vec![ClientEvent::Connected]
+    );
+
+    // The only slot is taken
+    session.add_client();
+    for _ in 0..10 {
+        session.step();
+    }
+    assert_eq!(
+        client_events(&mut session.clients[1]),
+        vec![ClientEvent::ServerFull]
+    );
+
+    // A token only admits one client
+    let token = ConnectToken::generate(
+        session.token_key.as_ref().unwrap(),
+        7,
+        vec![session.server_addr],
+        Duration::from_secs(30),
+        SystemTime::now(),
+    );
+    session.server.disconnect(0, session.network.now());
+    for _ in 0..10 {
+        session.step();
+    }
+    let mut first = Client::with_transport(session.network.bind(client_addr(11)).unwrap());
+    let mut second = Client::with_transport(session.network.bind(client_addr(12)).unwrap());
+    first.connect_with_token(token.clone()).unwrap();
+    for _ in 0..10 {
+        session.step();
+        while first.recv().is_ok() {}
+        first.send_next().unwrap();
+    }
+    assert_eq!(client_events(&mut first), vec![ClientEvent::Connected]);
+    let first_id = session.server.client_id(client_addr(11)).unwrap();
+    assert_eq!(session.server.client_user_id(first_id), Some(7));
+    second.connect_with_token(token).unwrap();
+    for _ in 0..10 {
+        session.step();
+        while second.recv().is_ok() {}
+        second.send_next().unwrap();
+    }
+    assert_eq!(
+        client_events(&mut second),
+        vec![ClientEvent::ConnectionDenied]
+    );
+
+    // Requests keep going out until the attempt times out
+    let nowhere = "10.0.9.9:40000".parse().unwrap();
+    idle.connect(nowhere).unwrap();
+    let start = session.network.now();
+    let mut sent = 1;
+    while idle.state().unwrap().0 != ConnectionState::Disconnected {
+        session.network.advance(Duration::from_millis(16));
+        if idle.send_next().unwrap() > 0 {
+            sent += 1;
+        }
+    }
+    let waited = session.network.now() - start;
+    assert!(waited > Duration::from_secs(5) && waited < Duration::from_secs(6));
+    assert!((45..=55).contains(&sent));
+    assert_eq!(client_events(&mut idle), vec![ClientEvent::Timeout]);
+}
+
+#[test]
+fn test_token_server_fallback() {
+    let mut session = Session::new(9, LinkConfig::default(), 1);
+    // Leave the only slot free
+    session.clients.clear();
+    session.to_clients.clear();
+    let token = |servers: Vec<SocketAddr>| ConnectToken {
+        server_addresses: servers,
+        ..ConnectToken::generate(
+            session.token_key.as_ref().unwrap(),
+            1,
+            vec![session.server_addr],
+            Duration::from_secs(30),
+            SystemTime::now(),
+        )
+    };
+    let nowhere = "10.0.9.9:40000".parse().unwrap();
+    let first = token(vec![nowhere, session.server_addr]);
+    let empty = token(Vec::new());
+
+    let mut client = Client::with_transport(session.network.bind(client_addr(1)).unwrap());
+    assert_eq!(
+        client.connect_with_token(empty).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+
+    // The first server never answers, the second takes us
+    client.connect_with_token(first).unwrap();
+    session.clients.push(client);
+    session.to_clients.push(Vec::new());
+    for _ in 0..500 {
+        session.step();
+    }
+    assert_eq!(
+        client_events(&mut session.clients[0]),
+        vec![ClientEvent::Connected]
+    );
+    assert!(session.server.client_id(client_addr(1)).is_some());
+}
+
+#[test]
+fn test_tokens_name_the_public_address() {
+    let network = VirtualNetwork::new(6);
+    let bound: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+    let public: SocketAddr = "203.0.113.1:40000".parse().unwrap();
+    let token_key = Keys::generate().send;
+    let mut server = Server::with_transport(network.bind(bound).unwrap(), 1504, 2).unwrap();
+    server.require_tokens(token_key, public);
+
+    let mut connect = |i: usize, named: SocketAddr| {
+        let token = ConnectToken::generate(
+            &token_key,
+            100 + i as u64,
+            vec![named],
+            Duration::from_secs(30),
+            SystemTime::now(),
+        );
+        // Behind NAT the token names the public address, the client
+        // still sends to the one the server is bound to
+        let mut client = Client::with_transport(network.bind(client_addr(i)).unwrap());
+        client
+            .connect_with_token(ConnectToken {
+                server_addresses: vec![bound],
+                ..token
+            })
+            .unwrap();
+        for _ in 0..10 {
+            network.advance(STEP);
+            server.update(network.now()).unwrap();
+            while client.recv().is_ok() {}
+            client.send_next().unwrap();
+        }
+        client_events(&mut client)
+    };
+
+    assert_eq!(connect(0, bound), vec![ClientEvent::ConnectionDenied]);
+    assert_eq!(connect(1, public), vec![ClientEvent::Connected]);
+    let id = server.client_id(client_addr(1)).unwrap();
+    assert_eq!(server.client_user_id(id), Some(101));
+}
+
+#[test]
+fn test_delivery_receipts() {
+    let link = LinkConfig {
+        latency: Latency::Uniform(Duration::from_millis(30), Duration::from_millis(60)),
+        loss: BurstLoss::uniform(0.1),
+        ..LinkConfig::default()
+    };
+    let mut session = Session::new(6, link, 1);
+    while !session.connected() {
+        session.step();
+    }
+    session.clients[0].events().for_each(drop);
+
+    // Every reliable message is reported once, unreliable ones never are
+    let mut queued = Vec::new();
+    for i in 0..100u32 {
+        let msg = i.to_be_bytes().to_vec();
+        queued.push(session.clients[0].queue_message(0, msg.clone()).unwrap());
+        session.clients[0].queue_message(2, msg).unwrap();
+        session.step();
+    }
+    for _ in 0..200 {
+        session.step();
+    }
+    let mut acked = Vec::new();
+    for event in client_events(&mut session.clients[0]) {
+        match event {
+            ClientEvent::MessageAcked(id, time) => {
+                // At least the shortest round trip
+                assert!(time >= Duration::from_millis(60));
+                acked.push(id);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    acked.sort_by_key(|id| id.sequence.0);
+    assert_eq!(acked, queued);
+
+    // Nothing from the server gets through until its deadline has passed
+    let dead = LinkConfig {
+        loss: BurstLoss::uniform(1.0),
+        ..LinkConfig::default()
+    };
+    session.network.set_link(session.server_addr, dead);
+    session.receipts.clear();
+    let late = session
+        .server
+        .send_to_with_deadline(
+            0,
+            0,
+            b"late",
+            session.network.now() + Duration::from_millis(500),
+        )
+        .unwrap();
+    for _ in 0..40 {
+        session.step();
+    }
+    assert_eq!(session.receipts, vec![ServerEvent::MessageGivenUp(0, late)]);
+
+    // The ordered channel carries on past the message that never made it
+    session.network.set_link(session.server_addr, link);
+    let after = session.server.send_to(0, 0, b"after").unwrap();
+    for _ in 0..60 {
+        session.step();
+    }
+    assert_eq!(
+        session.clients[0].recv_messages(),
+        Some(vec![(0, b"after".to_vec())])
+    );
+    assert!(matches!(
+        session.receipts[..],
+        [_, ServerEvent::MessageAcked(0, id, _)] if id == after
+    ));
+}
+
+// Checks each sent sequence gets at most one outcome and, unless it was
+// sent in the last second, exactly one
+fn check_outcomes(sent: &[Sequence], outcomes: &[(Sequence, bool)]) {
+    let mut resolved = HashMap::new();
+    for (sequence, acked) in outcomes {
+        assert!(sent.contains(sequence), "outcome for unsent {:?}", sequence);
+        assert!(resolved.insert(*sequence, *acked).is_none());
+    }
+    let recent = sent.len().saturating_sub(60);
+    for sequence in &sent[..recent] {
+        assert!(
+            resolved.contains_key(sequence),
+            "no outcome for {:?}",
+            sequence
+        );
+    }
+    assert!(resolved.values().any(|acked| *acked));
+    assert!(resolved.values().any(|acked| !*acked));
+}
+
+#[test]
+fn test_packet_outcomes() {
+    let link = LinkConfig {
+        latency: Latency::Uniform(Duration::from_millis(30), Duration::from_millis(60)),
+        loss: BurstLoss::uniform(0.1),
+        ..LinkConfig::default()
+    };
+    let mut session = Session::new(8, link, 1);
+    let mut client_sent = Vec::new();
+    let mut client_outcomes = Vec::new();
+    for i in 0..300u32 {
+        if session.connected() {
+            let msg = i.to_be_bytes().to_vec();
+            session.clients[0].queue_message(0, msg.clone()).unwrap();
+            session.server.send_to(0, 0, &msg).unwrap();
+        }
+        session.step();
+        for event in session.clients[0].events() {
+            match event {
+                ClientEvent::PacketSent(sequence) => client_sent.push(sequence),
+                ClientEvent::PacketAcked(sequence) => client_outcomes.push((sequence, true)),
+                ClientEvent::PacketLost(sequence) => client_outcomes.push((sequence, false)),
+                _ => {}
+            }
+        }
+    }
+    check_outcomes(&client_sent, &client_outcomes);
+
+    let mut server_sent = Vec::new();
+    let mut server_outcomes = Vec::new();
+    for event in session.packets {
+        match event {
+            ServerEvent::PacketSent(0, sequence) => server_sent.push(sequence),
+            ServerEvent::PacketAcked(0, sequence) => server_outcomes.push((sequence, true)),
+            ServerEvent::PacketLost(0, sequence) => server_outcomes.push((sequence, false)),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    check_outcomes(&server_sent, &server_outcomes);
+}